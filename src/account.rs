@@ -0,0 +1,183 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::config::{default_mux_files, AccountConfig, BackendConfig, LayerConfig, MountConfig, TokenStoreKind};
+use crate::odrive::Provider;
+use crate::token_store::CredentialStore;
+
+/// Where an account's settings come from without a config file.
+///
+/// Without `PAPERFS_ACCOUNTS` there's a single unnamed account, configured
+/// by the plain env vars and mounted at `/zotero`. A named account looks up
+/// `PAPERFS_ACCOUNT_<NAME>_<VAR>` first and falls back to the plain `<VAR>`
+/// shared by all accounts, except for what has to be its own.
+#[derive(Debug, Clone)]
+pub struct AccountEnv {
+    name: Option<String>,
+}
+
+impl AccountEnv {
+    pub fn single() -> Self {
+        AccountEnv { name: None }
+    }
+
+    /// Names may only use ascii letters, digits, `-` and `_`, they end up in
+    /// urls and env var names.
+    pub fn named(name: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "invalid account name {:?}", name);
+        Ok(AccountEnv { name: Some(name.to_string()) })
+    }
+
+    /// The accounts listed in `PAPERFS_ACCOUNTS`, or the single unnamed one.
+    pub fn from_env() -> anyhow::Result<Vec<Self>> {
+        let Ok(names) = std::env::var("PAPERFS_ACCOUNTS") else {
+            return Ok(vec![AccountEnv::single()]);
+        };
+        let mut accounts = Vec::new();
+        for name in names.split([' ', ',']).filter(|s| !s.is_empty()) {
+            let account = AccountEnv::named(name)?;
+            anyhow::ensure!(!accounts.iter().any(|a: &AccountEnv| a.name() == name), "duplicate account {}", name);
+            accounts.push(account);
+        }
+        anyhow::ensure!(!accounts.is_empty(), "PAPERFS_ACCOUNTS lists no account");
+        Ok(accounts)
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+
+    /// Name of the account's own env var for `var`.
+    pub fn scoped(&self, var: &str) -> String {
+        match self.name.as_ref() {
+            Some(name) => format!("PAPERFS_ACCOUNT_{}_{}", name.to_ascii_uppercase().replace('-', "_"), var),
+            None => var.to_string(),
+        }
+    }
+
+    /// The account's own `var`, or the shared one.
+    pub fn var(&self, var: &str) -> Option<String> {
+        std::env::var(self.scoped(var)).ok().or_else(|| std::env::var(var).ok())
+    }
+
+    /// The account's own `var` only, for what can't be shared.
+    pub fn own(&self, var: &str) -> Option<String> {
+        std::env::var(self.scoped(var)).ok()
+    }
+
+    /// Where the dav service is mounted.
+    pub fn mount_prefix(&self) -> String {
+        self.own("PAPERFS_MOUNT_PREFIX").unwrap_or_else(|| match self.name.as_ref() {
+            Some(name) => format!("/{}", name),
+            None => "/zotero".to_string(),
+        })
+    }
+
+    pub fn token_file(&self) -> PathBuf {
+        PathBuf::from(self.own("PAPERFS_TOKEN_FILE").unwrap_or_else(|| match self.name.as_ref() {
+            Some(name) => format!("app_data.{}.json", name),
+            None => "app_data.json".to_string(),
+        }))
+    }
+
+    /// The account's part of a state dir shared by all accounts.
+    pub fn state_dir(&self, dir: &Path) -> PathBuf {
+        match self.name.as_ref() {
+            Some(name) => dir.join(name),
+            None => dir.to_path_buf(),
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(&self, var: &str) -> anyhow::Result<Option<T>> {
+        self.var(var).map(|s| s.parse::<T>()).transpose()
+            .map_err(|_| anyhow::anyhow!("invalid {}", self.scoped(var)))
+    }
+
+    /// The account's session settings.
+    pub fn config(&self) -> anyhow::Result<AccountConfig> {
+        let token_store = match self.var("PAPERFS_TOKEN_STORE").as_deref() {
+            None | Some("file") => TokenStoreKind::File,
+            Some("memory") => TokenStoreKind::Memory,
+            Some(other) => anyhow::bail!("invalid {}: {}", self.scoped("PAPERFS_TOKEN_STORE"), other),
+        };
+        let credential_name = match self.name.as_ref() {
+            Some(name) => format!("onedrive_refresh_token_{}", name),
+            None => "onedrive_refresh_token".to_string(),
+        };
+        Ok(AccountConfig {
+            provider: Provider::Microsoft,
+            client_id: self.var("ONEDRIVE_CLIENT_ID")
+                .with_context(|| format!("{} not provided", self.scoped("ONEDRIVE_CLIENT_ID")))?,
+            client_secret: self.var("ONEDRIVE_CLIENT_SECRET"),
+            revocation_url: self.var("ONEDRIVE_REVOCATION_URL"),
+            // national clouds, single tenant apps, or a fake server for testing
+            authority: self.var("ONEDRIVE_AUTHORITY"),
+            tenant: self.var("ONEDRIVE_TENANT"),
+            graph_url: self.var("ONEDRIVE_GRAPH_URL"),
+            scopes: self.var("ONEDRIVE_SCOPES")
+                .map(|s| s.split([' ', ',']).filter(|s| !s.is_empty()).map(String::from).collect()),
+            app_folder: self.parse("ONEDRIVE_APP_FOLDER")?.unwrap_or(false),
+            app_only: self.parse("ONEDRIVE_APP_ONLY")?.unwrap_or(false),
+            client_cert_key_file: self.var("ONEDRIVE_CLIENT_CERT_KEY_FILE").map(PathBuf::from),
+            client_cert_thumbprint: self.var("ONEDRIVE_CLIENT_CERT_THUMBPRINT"),
+            drive_user: self.var("ONEDRIVE_DRIVE_USER"),
+            drive_id: self.var("ONEDRIVE_DRIVE_ID"),
+            token_store,
+            token_file: Some(self.token_file()),
+            token_key: self.var("PAPERFS_TOKEN_KEY"),
+            token_key_file: self.var("PAPERFS_TOKEN_KEY_FILE").map(PathBuf::from),
+            refresh_token_file: CredentialStore::locate(&self.scoped("ONEDRIVE_REFRESH_TOKEN"), &credential_name)
+                .map(|credential| credential.path().to_path_buf()),
+            refresh_margin: self.parse("PAPERFS_REFRESH_MARGIN")?,
+        })
+    }
+
+    /// The account's drive under its mount prefix, with the layers the
+    /// state dir env vars enable.
+    pub fn mount(&self) -> anyhow::Result<MountConfig> {
+        let root = self.var("ONEDRIVE_ROOT")
+            .with_context(|| format!("{} not provided", self.scoped("ONEDRIVE_ROOT")))?;
+        let mut layers = Vec::new();
+        // upload sessions stage writes on disk themselves, no need to buffer twice
+        match std::env::var("PAPERFS_UPLOAD_DIR") {
+            Ok(dir) => layers.push(LayerConfig::Upload {
+                dir: self.state_dir(Path::new(&dir)),
+                chunk_size: self.parse("PAPERFS_UPLOAD_CHUNK_SIZE")?,
+            }),
+            Err(_) => layers.push(LayerConfig::Buffer {
+                mem_threshold: self.parse("PAPERFS_BUF_MEM_THRESHOLD")?,
+                spill_dir: self.var("PAPERFS_BUF_SPILL_DIR").map(PathBuf::from),
+            }),
+        }
+        if let Ok(dir) = std::env::var("PAPERFS_JOURNAL_DIR") {
+            layers.push(LayerConfig::Journal { dir: self.state_dir(Path::new(&dir)) });
+        }
+        if let Ok(dir) = std::env::var("PAPERFS_CACHE_DIR") {
+            layers.push(LayerConfig::Cache {
+                dir: self.state_dir(Path::new(&dir)),
+                size: self.parse("PAPERFS_CACHE_SIZE")?,
+            });
+        }
+        if let Some(ttl) = self.parse("PAPERFS_META_CACHE_TTL")? {
+            layers.push(LayerConfig::MetaCache { ttl });
+        }
+        layers.push(LayerConfig::Mux { files: default_mux_files() });
+        Ok(MountConfig {
+            prefix: self.mount_prefix(),
+            backend: BackendConfig::Onedrive { account: self.name().to_string() },
+            root,
+            max_body_size: self.parse("PAPERFS_DAV_MAX_BODY_SIZE")?,
+            layers,
+        })
+    }
+}
+
+/// What the index page needs to sign an account in.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct AccountInfo {
+    pub name: String,
+    pub mounts: Vec<String>,
+    pub api: String,
+}
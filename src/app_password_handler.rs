@@ -0,0 +1,85 @@
+use axum::{Json, Router, extract::{Path, State}, response::IntoResponse, routing::{delete, get}};
+use http::{HeaderMap, StatusCode};
+use serde::Serialize;
+
+use crate::app_passwords::{AppPassword, NewAppPassword};
+use crate::basic_auth::BasicAuth;
+use crate::types::Response;
+
+/// A new app password, the only time the password is shown.
+#[derive(Serialize)]
+struct Created {
+    #[serde(flatten)]
+    info: AppPassword,
+    password: String,
+}
+
+async fn list(State(auth): State<BasicAuth>, headers: HeaderMap) -> axum::response::Response {
+    let user = match auth.user(&headers).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    Json(Response {
+        code: StatusCode::OK.as_u16(),
+        msg: "success".to_string(),
+        body: auth.app_passwords().list(&user),
+    }).into_response()
+}
+
+async fn create(State(auth): State<BasicAuth>, headers: HeaderMap, Json(new): Json<NewAppPassword>) -> axum::response::Response {
+    let user = match auth.user(&headers).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    match auth.app_passwords().create(&user, new).await {
+        Ok((info, password)) => (StatusCode::OK, Json(Response {
+            code: StatusCode::OK.as_u16(),
+            msg: "success".to_string(),
+            body: Some(Created { info, password }),
+        })).into_response(),
+        // failing to save is on us, anything else on the request
+        Err(e) if e.is::<std::io::Error>() => (StatusCode::INTERNAL_SERVER_ERROR, Json(Response {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            msg: format!("failed to save app passwords: {}", e),
+            body: None::<Created>,
+        })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(Response {
+            code: StatusCode::BAD_REQUEST.as_u16(),
+            msg: e.to_string(),
+            body: None::<Created>,
+        })).into_response(),
+    }
+}
+
+async fn revoke(State(auth): State<BasicAuth>, headers: HeaderMap, Path(id): Path<String>) -> axum::response::Response {
+    let user = match auth.user(&headers).await {
+        Ok(user) => user,
+        Err(resp) => return resp,
+    };
+    match auth.app_passwords().revoke(&user, &id).await {
+        Ok(true) => (StatusCode::OK, Json(Response {
+            code: StatusCode::OK.as_u16(),
+            msg: "success".to_string(),
+            body: (),
+        })).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(Response {
+            code: StatusCode::NOT_FOUND.as_u16(),
+            msg: format!("app password {} not found", id),
+            body: (),
+        })).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Response {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            msg: format!("failed to save app passwords: {}", e),
+            body: (),
+        })).into_response(),
+    }
+}
+
+/// Each dav user manages their own app passwords, signed in with their
+/// own password.
+pub fn app_password_api_router(auth: BasicAuth) -> Router {
+    Router::new()
+        .route("/", get(list).post(create))
+        .route("/{id}", delete(revoke))
+        .with_state(auth)
+}
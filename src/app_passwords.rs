@@ -0,0 +1,385 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use dav_server::davpath::DavPath;
+use http::{Method, Request};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::file_ls::covers;
+use crate::token_store::restrict_permissions;
+
+/// `last_used` is only saved once it's this stale, clients send the
+/// password with every request.
+const LAST_USED_RESOLUTION: Duration = Duration::minutes(1);
+
+const MAX_LABEL_LEN: usize = 100;
+
+/// What the requests made with an app password may do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Scope {
+    /// only the methods that don't change anything
+    #[serde(default)]
+    pub read_only: bool,
+    /// url path the password is limited to, including the mount prefix
+    pub path_prefix: Option<String>,
+}
+
+impl Scope {
+    /// Whether `req` stays within the scope, its `Destination` included.
+    pub fn allows<B>(&self, req: &Request<B>) -> bool {
+        let method = req.method();
+        if self.read_only && !(method == Method::GET || method == Method::HEAD || method == Method::OPTIONS || method.as_str() == "PROPFIND") {
+            return false;
+        }
+        let Some(prefix) = self.path_prefix.as_deref() else {
+            return true;
+        };
+        let Ok(prefix) = DavPath::new(prefix) else {
+            return false;
+        };
+        // normalized, a `..` can't climb out of the prefix
+        let under = |path: &str| DavPath::new(path).is_ok_and(|path| covers(&prefix, &path));
+        if !under(req.uri().path()) {
+            return false;
+        }
+        match req.headers().get("destination") {
+            Some(destination) => destination.to_str().ok()
+                .and_then(|d| d.parse::<http::Uri>().ok())
+                .is_some_and(|uri| under(uri.path())),
+            None => true,
+        }
+    }
+}
+
+/// An app password as the api lists it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppPassword {
+    pub id: String,
+    /// the dav user it signs in as
+    pub user: String,
+    /// which device or client it's for
+    pub label: String,
+    #[serde(flatten)]
+    pub scope: Scope,
+    pub created_at: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// What the api takes to create an app password.
+#[derive(Deserialize, Debug)]
+pub struct NewAppPassword {
+    pub label: String,
+    #[serde(flatten)]
+    pub scope: Scope,
+    /// never without one
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    info: AppPassword,
+    /// sha256 of the password, it's random so a slow hash buys nothing
+    hash: String,
+}
+
+fn digest(password: &str) -> String {
+    BASE64URL.encode(Sha256::digest(password))
+}
+
+/// Per-device passwords of the dav users, persisted hashed to a json file.
+///
+/// Each one signs in as its user on its own, so a lost device's password
+/// is revoked without changing the user's or the other devices'.
+#[derive(Clone)]
+pub struct AppPasswords {
+    inner: Arc<AppPasswordsInner>,
+}
+
+struct AppPasswordsInner {
+    path: PathBuf,
+    state: Mutex<State>,
+    /// version of the state last written to `path`
+    saved: tokio::sync::Mutex<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    records: Vec<Record>,
+    version: u64,
+}
+
+impl AppPasswords {
+    /// Load the app passwords saved in `path`, starting empty if it doesn't
+    /// exist.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let records: Vec<Record> = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        log::info!("loaded {} app passwords from {}", records.len(), path.display());
+        Ok(AppPasswords {
+            inner: Arc::new(AppPasswordsInner {
+                path,
+                state: Mutex::new(State { records, version: 0 }),
+                saved: tokio::sync::Mutex::new(0),
+            }),
+        })
+    }
+
+    /// The app passwords of `user`, expired ones included.
+    pub fn list(&self, user: &str) -> Vec<AppPassword> {
+        self.inner.state.lock().unwrap().records.iter()
+            .filter(|r| r.info.user == user)
+            .map(|r| r.info.clone())
+            .collect()
+    }
+
+    /// Create an app password for `user`, the password itself is only
+    /// returned here. Fails with an `io::Error` if it couldn't be saved, it
+    /// isn't kept then.
+    pub async fn create(&self, user: &str, new: NewAppPassword) -> anyhow::Result<(AppPassword, String)> {
+        let label = new.label.trim();
+        anyhow::ensure!(!label.is_empty() && label.len() <= MAX_LABEL_LEN, "the label must be 1 to {} bytes", MAX_LABEL_LEN);
+        let mut scope = new.scope;
+        if let Some(prefix) = scope.path_prefix.take() {
+            anyhow::ensure!(prefix.starts_with('/') && DavPath::new(&prefix).is_ok(), "invalid path prefix {:?}", prefix);
+            // `/` alone is no limit at all
+            scope.path_prefix = Some(prefix.trim_end_matches('/').to_string()).filter(|p| !p.is_empty());
+        }
+        let now = Utc::now();
+        anyhow::ensure!(new.expires_at.is_none_or(|at| at > now), "expires_at is in the past");
+        let password = BASE64URL.encode(rand::random::<[u8; 24]>());
+        let info = AppPassword {
+            id: uuid::Uuid::new_v4().to_string(),
+            user: user.to_string(),
+            label: label.to_string(),
+            scope,
+            created_at: now,
+            last_used: None,
+            expires_at: new.expires_at,
+        };
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            state.records.push(Record { info: info.clone(), hash: digest(&password) });
+            state.version += 1;
+        }
+        if let Err(e) = self.save().await {
+            let mut state = self.inner.state.lock().unwrap();
+            state.records.retain(|r| r.info.id != info.id);
+            state.version += 1;
+            return Err(e.into());
+        }
+        log::info!("created app password {} ({}) for {}", info.id, info.label, user);
+        Ok((info, password))
+    }
+
+    /// Revoke the app password `id` of `user`, returns whether it existed.
+    /// It's kept if the revocation couldn't be saved.
+    pub async fn revoke(&self, user: &str, id: &str) -> std::io::Result<bool> {
+        let removed = {
+            let mut state = self.inner.state.lock().unwrap();
+            let index = state.records.iter().position(|r| r.info.user == user && r.info.id == id);
+            let removed = index.map(|index| (index, state.records.remove(index)));
+            if removed.is_some() {
+                state.version += 1;
+            }
+            removed
+        };
+        let Some((index, record)) = removed else {
+            return Ok(false);
+        };
+        if let Err(e) = self.save().await {
+            let mut state = self.inner.state.lock().unwrap();
+            let index = index.min(state.records.len());
+            state.records.insert(index, record);
+            state.version += 1;
+            return Err(e);
+        }
+        log::info!("revoked app password {} of {}", id, user);
+        Ok(true)
+    }
+
+    /// The scope of the app password `password` of `user`, unless it's
+    /// unknown or expired. Marks it used.
+    pub fn check(&self, user: &str, password: &str) -> Option<Scope> {
+        let hash = digest(password);
+        let now = Utc::now();
+        let (scope, used) = {
+            let mut guard = self.inner.state.lock().unwrap();
+            let state = &mut *guard;
+            let record = state.records.iter_mut().find(|r| r.info.user == user && r.hash == hash)?;
+            if record.info.expires_at.is_some_and(|at| at <= now) {
+                log::warn!("dav auth failed: app password {} ({}) of {} expired", record.info.id, record.info.label, user);
+                return None;
+            }
+            let used = record.info.last_used.is_none_or(|at| now - at >= LAST_USED_RESOLUTION);
+            if used {
+                record.info.last_used = Some(now);
+                state.version += 1;
+            }
+            (record.info.scope.clone(), used)
+        };
+        if used {
+            let app_passwords = self.clone();
+            // losing a last_used stamp is harmless, it's retried on next use
+            tokio::spawn(async move {
+                let _ = app_passwords.save().await;
+            });
+        }
+        Some(scope)
+    }
+
+    /// Write the current state if nothing newer was written yet, readable
+    /// by us only.
+    async fn save(&self) -> std::io::Result<()> {
+        let mut saved = self.inner.saved.lock().await;
+        let (version, json) = {
+            let state = self.inner.state.lock().unwrap();
+            if state.version <= *saved {
+                return Ok(());
+            }
+            (state.version, serde_json::to_vec(&state.records).expect("app passwords serialize"))
+        };
+        let tmp = self.inner.path.with_extension("json.tmp");
+        let res = async {
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(&tmp).await?;
+            // the temp file may predate us with a wider mode
+            restrict_permissions(&tmp).await?;
+            file.write_all(&json).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &self.inner.path).await
+        }.await;
+        match res {
+            Ok(()) => {
+                *saved = version;
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("failed to save app passwords to {}: {}", self.inner.path.display(), e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(prefix: &str) -> Scope {
+        Scope { read_only: false, path_prefix: Some(prefix.to_string()) }
+    }
+
+    fn req(method: &str, uri: &str, destination: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(destination) = destination {
+            builder = builder.header("destination", destination);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn read_only_allows_reads() {
+        let scope = Scope { read_only: true, path_prefix: None };
+        for method in ["GET", "HEAD", "OPTIONS", "PROPFIND"] {
+            assert!(scope.allows(&req(method, "/zotero/a", None)), "{}", method);
+        }
+        for method in ["PUT", "DELETE", "MKCOL", "MOVE", "COPY", "PROPPATCH", "LOCK"] {
+            assert!(!scope.allows(&req(method, "/zotero/a", None)), "{}", method);
+        }
+    }
+
+    #[test]
+    fn prefix_covers_whole_segments() {
+        let scope = scope("/zotero/sub");
+        assert!(scope.allows(&req("GET", "/zotero/sub", None)));
+        assert!(scope.allows(&req("GET", "/zotero/sub/", None)));
+        assert!(scope.allows(&req("PUT", "/zotero/sub/a/b.pdf", None)));
+        assert!(!scope.allows(&req("GET", "/zotero/subway", None)));
+        assert!(!scope.allows(&req("GET", "/zotero", None)));
+        assert!(!scope.allows(&req("GET", "/other/sub", None)));
+    }
+
+    #[test]
+    fn prefix_cant_be_climbed_out_of() {
+        let scope = scope("/zotero/sub");
+        assert!(!scope.allows(&req("GET", "/zotero/sub/../other", None)));
+        assert!(!scope.allows(&req("GET", "/zotero/sub/%2e%2e/other", None)));
+        assert!(!scope.allows(&req("GET", "/zotero/sub/..", None)));
+        assert!(scope.allows(&req("GET", "/zotero/sub/a/../b", None)));
+        assert!(scope.allows(&req("GET", "/zotero/s%75b/a", None)), "percent encoded segments are decoded");
+    }
+
+    #[test]
+    fn destination_stays_in_the_prefix() {
+        let scope = scope("/zotero/sub");
+        assert!(scope.allows(&req("MOVE", "/zotero/sub/a", Some("http://host/zotero/sub/b"))));
+        assert!(scope.allows(&req("COPY", "/zotero/sub/a", Some("/zotero/sub/b"))));
+        assert!(!scope.allows(&req("MOVE", "/zotero/sub/a", Some("http://host/zotero/other/a"))));
+        assert!(!scope.allows(&req("MOVE", "/zotero/sub/a", Some("http://host/zotero/sub/../other/a"))));
+        assert!(!scope.allows(&req("COPY", "/zotero/sub/a", Some("http://host/zotero/subway/a"))));
+        assert!(!scope.allows(&req("MOVE", "/zotero/sub/a", Some("not a uri"))));
+        // moving in from outside is as bad as moving out
+        assert!(!scope.allows(&req("MOVE", "/zotero/other/a", Some("http://host/zotero/sub/a"))));
+    }
+
+    #[tokio::test]
+    async fn create_check_and_revoke() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app_passwords.json");
+        let app_passwords = AppPasswords::open(&path).unwrap();
+        let new = |label: &str, prefix: Option<&str>| NewAppPassword {
+            label: label.to_string(),
+            scope: Scope { read_only: false, path_prefix: prefix.map(str::to_string) },
+            expires_at: None,
+        };
+        assert!(app_passwords.create("alice", new(" ", None)).await.is_err());
+        assert!(app_passwords.create("alice", new("phone", Some("zotero"))).await.is_err());
+        let (root, _) = app_passwords.create("alice", new("laptop", Some("/"))).await.unwrap();
+        assert!(root.scope.path_prefix.is_none(), "/ is no limit");
+        let (info, password) = app_passwords.create("alice", new("phone", Some("/zotero/sub/"))).await.unwrap();
+        assert_eq!(info.scope.path_prefix.as_deref(), Some("/zotero/sub"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        drop(app_passwords);
+
+        let reopened = AppPasswords::open(&path).unwrap();
+        assert!(reopened.check("bob", &password).is_none(), "bound to its user");
+        assert!(reopened.check("alice", "wrong").is_none());
+        let scope = reopened.check("alice", &password).unwrap();
+        assert_eq!(scope.path_prefix.as_deref(), Some("/zotero/sub"));
+        assert!(!reopened.revoke("bob", &info.id).await.unwrap(), "only its user revokes it");
+        assert!(reopened.revoke("alice", &info.id).await.unwrap());
+        assert!(reopened.check("alice", &password).is_none());
+        assert_eq!(AppPasswords::open(&path).unwrap().list("alice").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_passwords_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_passwords = AppPasswords::open(dir.path().join("app_passwords.json")).unwrap();
+        let past = NewAppPassword { label: "old".to_string(), scope: Scope::default(), expires_at: Some(Utc::now() - Duration::minutes(1)) };
+        assert!(app_passwords.create("alice", past).await.is_err());
+        let soon = NewAppPassword { label: "soon".to_string(), scope: Scope::default(), expires_at: Some(Utc::now() + Duration::milliseconds(100)) };
+        let (_, password) = app_passwords.create("alice", soon).await.unwrap();
+        assert!(app_passwords.check("alice", &password).is_some());
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(app_passwords.check("alice", &password).is_none());
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use argon2::password_hash::{phc::PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::Argon2;
+use axum::body::Body;
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use http::{header, HeaderMap, Method, Request, StatusCode};
+use sha2::{Digest, Sha256};
+use tower_service::Service;
+
+use crate::app_passwords::{AppPasswords, Scope};
+use crate::config::AuthConfig;
+
+const DEFAULT_REALM: &str = "paperfs";
+
+/// The authenticated user of a dav request, the lock principal.
+#[derive(Debug, Clone)]
+pub struct AuthUser(pub String);
+
+/// Checks a configured user: Basic auth can't carry a `:` in the name, and
+/// the hash has to be argon2 or bcrypt.
+pub fn check_user(name: &str, hash: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(!name.is_empty() && !name.contains(':'), "invalid user name {:?}", name);
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).map_err(|e| anyhow::anyhow!("user {}: invalid argon2 hash: {}", name, e))?;
+    } else if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+        hash.parse::<bcrypt::HashParts>().with_context(|| format!("user {}: invalid bcrypt hash", name))?;
+    } else {
+        anyhow::bail!("user {}: unsupported password hash, use argon2 or bcrypt", name);
+    }
+    Ok(())
+}
+
+/// An argon2id hash of `password` with a random salt.
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let hash = Argon2::default().hash_password(password.as_bytes())
+        .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// The users allowed on the dav mounts, with their password hashes.
+pub struct Users {
+    realm: String,
+    hashes: HashMap<String, String>,
+}
+
+impl Users {
+    /// `None` without any users, the mounts are then open to anyone.
+    pub fn from_config(auth: &AuthConfig) -> anyhow::Result<Option<Self>> {
+        let mut hashes: HashMap<String, String> = auth.users.iter()
+            .map(|user| (user.name.clone(), user.password_hash.clone()))
+            .collect();
+        if let Some(path) = auth.htpasswd_file.as_ref() {
+            let data = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+            for line in data.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+                let (name, hash) = line.split_once(':').with_context(|| format!("{}: invalid line {:?}", path.display(), line))?;
+                check_user(name, hash).with_context(|| path.display().to_string())?;
+                anyhow::ensure!(!hashes.contains_key(name), "duplicate user {}", name);
+                hashes.insert(name.to_string(), hash.to_string());
+            }
+        } else if hashes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Users {
+            realm: auth.realm.clone().unwrap_or_else(|| DEFAULT_REALM.to_string()),
+            hashes,
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+}
+
+/// The current users, swapped on reload, and their app passwords.
+#[derive(Clone)]
+pub struct BasicAuth {
+    users: Arc<ArcSwap<Option<Users>>>,
+    app_passwords: AppPasswords,
+    /// digests of the credentials that verified, the hashes are slow on
+    /// purpose and clients send the password with every request
+    verified: Arc<Mutex<HashSet<[u8; 32]>>>,
+}
+
+impl BasicAuth {
+    /// No users until the first [`BasicAuth::set`].
+    pub fn new(app_passwords: AppPasswords) -> Self {
+        BasicAuth {
+            users: Arc::default(),
+            app_passwords,
+            verified: Arc::default(),
+        }
+    }
+
+    pub fn set(&self, users: Option<Users>) {
+        self.users.store(Arc::new(users));
+        self.verified.lock().unwrap().clear();
+    }
+
+    pub fn app_passwords(&self) -> &AppPasswords {
+        &self.app_passwords
+    }
+
+    /// The user the request's credentials are valid for, and the scope when
+    /// they're an app password.
+    async fn authenticate(&self, users: &Users, headers: &HeaderMap) -> Option<(String, Option<Scope>)> {
+        let (name, password) = credentials(headers)?;
+        if !users.hashes.contains_key(&name) {
+            log::warn!("dav auth failed: unknown user {}", name);
+            return None;
+        }
+        // first, checking an app password is cheap
+        if let Some(scope) = self.app_passwords.check(&name, &password) {
+            return Some((name, Some(scope)));
+        }
+        if !self.verify_user(users, &name, &password).await {
+            log::warn!("dav auth failed: wrong password for {}", name);
+            return None;
+        }
+        Some((name, None))
+    }
+
+    /// The user the request signs in as with their own password, app
+    /// passwords can't manage app passwords. The response to send back
+    /// otherwise.
+    pub async fn user(&self, headers: &HeaderMap) -> Result<String, axum::response::Response> {
+        let users = self.users.load_full();
+        let Some(users) = users.as_ref().as_ref() else {
+            return Err((StatusCode::FORBIDDEN, "no dav users configured\n").into_response());
+        };
+        match credentials(headers) {
+            Some((name, password)) if self.verify_user(users, &name, &password).await => Ok(name),
+            Some((name, _)) => {
+                log::warn!("api auth failed for {}", name);
+                Err(challenge(&users.realm))
+            }
+            None => Err(challenge(&users.realm)),
+        }
+    }
+
+    /// Checks `password` against the user's hash.
+    async fn verify_user(&self, users: &Users, name: &str, password: &str) -> bool {
+        let Some(hash) = users.hashes.get(name) else {
+            return false;
+        };
+        let digest: [u8; 32] = Sha256::new()
+            .chain_update(name).chain_update([0])
+            .chain_update(password).chain_update([0])
+            .chain_update(hash)
+            .finalize().into();
+        if self.verified.lock().unwrap().contains(&digest) {
+            return true;
+        }
+        let (password, hash) = (password.to_string(), hash.clone());
+        if !tokio::task::spawn_blocking(move || verify(&password, &hash)).await.unwrap_or(false) {
+            return false;
+        }
+        self.verified.lock().unwrap().insert(digest);
+        true
+    }
+}
+
+/// Lets the request through if it signs in as one of the users with their
+/// own password, for the admin endpoints. Open while there are no users,
+/// like the mounts.
+pub async fn require_user(State(auth): State<BasicAuth>, req: Request<Body>, next: Next) -> axum::response::Response {
+    if auth.users.load().is_some() {
+        if let Err(resp) = auth.user(req.headers()).await {
+            return resp;
+        }
+    }
+    next.run(req).await
+}
+
+/// The name and password of Basic credentials.
+fn credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(BASE64.decode(credentials.trim()).ok()?).ok()?;
+    let (name, password) = credentials.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+/// Asks for Basic auth in front of `inner` while there are users, and
+/// passes the user on as an [`AuthUser`] extension. Requests made with an
+/// app password are refused outside of its scope.
+///
+/// OPTIONS goes through unauthenticated, clients probe the dav class with
+/// it before they send any credentials.
+#[derive(Clone)]
+pub struct RequireAuth<S> {
+    inner: S,
+    auth: BasicAuth,
+}
+
+impl<S> RequireAuth<S> {
+    pub fn new(inner: S, auth: BasicAuth) -> Self {
+        RequireAuth { inner, auth }
+    }
+}
+
+impl<S> Service<Request<Body>> for RequireAuth<S>
+where
+    S: Service<Request<Body>, Response = axum::response::Response, Error = std::convert::Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = axum::response::Response;
+    type Error = std::convert::Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let auth = self.auth.clone();
+        Box::pin(async move {
+            let users = auth.users.load_full();
+            if let Some(users) = users.as_ref().as_ref().filter(|_| req.method() != Method::OPTIONS) {
+                match auth.authenticate(users, req.headers()).await {
+                    Some((name, Some(scope))) if !scope.allows(&req) => {
+                        log::warn!("{} {} refused, outside the app password's scope for {}", req.method(), req.uri().path(), name);
+                        return Ok((StatusCode::FORBIDDEN, "outside the app password's scope\n").into_response());
+                    }
+                    Some((name, _)) => { req.extensions_mut().insert(AuthUser(name)); }
+                    None => return Ok(challenge(&users.realm)),
+                }
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+fn challenge(realm: &str) -> axum::response::Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm))],
+        "authentication required\n",
+    ).into_response()
+}
@@ -1,17 +1,46 @@
 use std::mem;
+use std::path::PathBuf;
 
 use opendal::raw::{oio as oio, LayeredAccess, OpRead, RpRead};
 use opendal::raw::{Access, Layer, OpWrite, RpWrite};
-use opendal::{Metadata, Result};
-
-use bytes::BufMut;
-
-#[derive(Debug, Copy, Clone)]
-pub struct BufLayer;
+use opendal::{Buffer, Error, ErrorKind, Metadata, Result};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+const DEFAULT_MEM_THRESHOLD: usize = 8 * 1024 * 1024;
+const SPILL_READ_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Buffers whole files before handing them to the inner writer.
+/// Files up to `mem_threshold` bytes stay in memory, larger ones spill to an
+/// anonymous temp file in `spill_dir`, which the OS removes once it's closed.
+/// One shot writers get the spill file memory mapped, so its pages are read
+/// from the file as they're sent instead of being held on the heap.
+#[derive(Debug, Clone)]
+pub struct BufLayer {
+    mem_threshold: usize,
+    spill_dir: PathBuf,
+}
 
 impl Default for BufLayer {
     fn default() -> Self {
-        Self { }
+        Self {
+            mem_threshold: DEFAULT_MEM_THRESHOLD,
+            spill_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+impl BufLayer {
+    pub fn mem_threshold(mut self, mem_threshold: usize) -> Self {
+        self.mem_threshold = mem_threshold;
+        self
+    }
+
+    pub fn spill_dir(mut self, spill_dir: impl Into<PathBuf>) -> Self {
+        self.spill_dir = spill_dir.into();
+        self
     }
 }
 
@@ -19,13 +48,14 @@ impl<A: Access> Layer<A> for BufLayer {
     type LayeredAccess = BufAccessor<A>;
 
     fn layer(&self, access: A) -> Self::LayeredAccess {
-        BufAccessor { access }
+        BufAccessor { access, layer: self.clone() }
     }
 }
 
 #[derive(Debug)]
 pub struct BufAccessor<A> where A: Access {
     access: A,
+    layer: BufLayer,
 }
 
 impl<A:Access> LayeredAccess for BufAccessor<A> {
@@ -53,7 +83,15 @@ impl<A:Access> LayeredAccess for BufAccessor<A> {
         args: OpWrite,
     ) -> Result<(RpWrite, Self::Writer)> {
         let (rp_write, writer) = self.access.write(path, args).await?;
-        Ok((rp_write, BufferedWriter { inner: writer, buffer: Vec::new() }))
+        let write_can_multi = self.access.info().native_capability().write_can_multi;
+        Ok((rp_write, BufferedWriter {
+            inner: writer,
+            buffer: Vec::new(),
+            spill: None,
+            mem_threshold: self.layer.mem_threshold,
+            spill_dir: self.layer.spill_dir.clone(),
+            write_can_multi,
+        }))
     }
 
     async fn list(
@@ -72,23 +110,94 @@ impl<A:Access> LayeredAccess for BufAccessor<A> {
 pub struct BufferedWriter<W> {
     inner: W,
     buffer: Vec<u8>,
+    spill: Option<File>,
+    mem_threshold: usize,
+    spill_dir: PathBuf,
+    write_can_multi: bool,
+}
+
+impl<W> BufferedWriter<W> {
+    async fn spill(&mut self, bs: Buffer) -> Result<()> {
+        let file = match &mut self.spill {
+            Some(file) => file,
+            None => {
+                let dir = self.spill_dir.clone();
+                let file = tokio::task::spawn_blocking(move || tempfile::tempfile_in(dir))
+                    .await
+                    .map_err(|e| Error::new(ErrorKind::Unexpected, "spill task failed").set_source(e))?
+                    .map_err(spill_error)?;
+                log::debug!("spill {} buffered bytes to {}", self.buffer.len(), self.spill_dir.display());
+                let mut file = File::from_std(file);
+                file.write_all(&mem::take(&mut self.buffer)).await.map_err(spill_error)?;
+                self.spill.insert(file)
+            }
+        };
+        for chunk in bs {
+            file.write_all(&chunk).await.map_err(spill_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn spill_error(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Unexpected, "buffer spill file io failed").set_source(e)
 }
 
 impl<W: oio::Write> oio::Write for BufferedWriter<W> {
     async fn write(&mut self, bs: opendal::Buffer) -> Result<()> {
         log::debug!("buffer {} bytes", bs.len());
-        self.buffer.put(bs);
-        Ok(())
+        if self.spill.is_none() && self.buffer.len() + bs.len() <= self.mem_threshold {
+            self.buffer.put(bs);
+            return Ok(());
+        }
+        self.spill(bs).await
     }
 
     async fn close(&mut self) -> Result<Metadata> {
-        log::debug!("write {} bytes", self.buffer.len());
-        self.inner.write(mem::replace(&mut self.buffer, Vec::new()).into()).await?;
+        let Some(mut file) = self.spill.take() else {
+            log::debug!("write {} bytes", self.buffer.len());
+            self.inner.write(mem::take(&mut self.buffer).into()).await?;
+            return self.inner.close().await;
+        };
+        file.flush().await.map_err(spill_error)?;
+        if !self.write_can_multi {
+            // one shot writers (eg. onedrive) only accept a single buffer
+            let file = file.into_std().await;
+            let map = tokio::task::spawn_blocking(move || {
+                // SAFETY: the spill file is anonymous and only this writer
+                // has it open, nothing can change it while it's mapped
+                unsafe { memmap2::Mmap::map(&file) }
+            })
+                .await
+                .map_err(|e| Error::new(ErrorKind::Unexpected, "spill task failed").set_source(e))?
+                .map_err(spill_error)?;
+            log::debug!("write {} bytes from mapped spill file", map.len());
+            self.inner.write(Bytes::from_owner(map).into()).await?;
+            return self.inner.close().await;
+        }
+        file.rewind().await.map_err(spill_error)?;
+        let mut written = 0;
+        loop {
+            let mut chunk = BytesMut::with_capacity(SPILL_READ_CHUNK);
+            while chunk.len() < SPILL_READ_CHUNK {
+                if file.read_buf(&mut chunk).await.map_err(spill_error)? == 0 {
+                    break;
+                }
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            written += chunk.len();
+            self.inner.write(chunk.freeze().into()).await?;
+        }
+        log::debug!("write {} bytes from spill file", written);
         self.inner.close().await
     }
 
     async fn abort(&mut self) -> Result<()> {
         log::debug!("abort");
+        self.buffer = Vec::new();
+        self.spill = None;
         self.inner.abort().await
     }
 }
@@ -0,0 +1,454 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use opendal::raw::{oio, Access, Layer, LayeredAccess, OpCopy, OpDelete, OpList, OpRead, OpRename, OpStat, OpWrite, RpCopy, RpDelete, RpList, RpRead, RpRename, RpWrite};
+use opendal::{Buffer, Error, ErrorKind, Metadata, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+const READ_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Caches file contents on local disk, evicting the least recently used
+/// files once `capacity` bytes are exceeded.
+///
+/// Every read stats the backend first and only serves the cached copy when
+/// its eTag (or size and last modified time if there is no eTag) still
+/// matches. Writes, deletes, copies and renames through the layer drop the
+/// affected entries.
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: Arc<Cache>,
+}
+
+impl CacheLayer {
+    pub fn open(dir: impl Into<PathBuf>, capacity: u64) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut state = CacheState::default();
+        let mut loaded = Vec::new();
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else { continue };
+            match path.extension().and_then(|s| s.to_str()) {
+                Some("json") => {
+                    let data = dir.join(format!("{}.data", id));
+                    let parsed = std::fs::read(&path).ok()
+                        .and_then(|json| serde_json::from_slice::<CacheEntry>(&json).ok());
+                    let data = std::fs::metadata(&data).and_then(|m| Ok((m.len(), m.modified()?)));
+                    match (parsed, data) {
+                        // a crash can leave the data short of the version it's for
+                        (Some(entry), Ok((len, used))) if entry.id == id && len == entry.version.size => loaded.push((used, entry)),
+                        _ => remove_entry_files(&dir, &id),
+                    }
+                }
+                Some("data") if !dir.join(format!("{}.json", id)).exists() => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+        // oldest first so the most recently used end up with the highest
+        // ticks, a hit sets the data file's mtime
+        loaded.sort_by_key(|(used, _)| *used);
+        for (_, entry) in loaded {
+            state.insert(&dir, entry);
+        }
+        let cache = Cache { dir, capacity: AtomicU64::new(capacity), state: Mutex::new(state) };
+        cache.evict();
+        log::info!("read cache loaded with {} bytes", cache.state.lock().unwrap().size);
+        Ok(CacheLayer { cache: Arc::new(cache) })
+    }
+
+    /// Change the size limit, evicting right away when it shrinks.
+    pub fn set_capacity(&self, capacity: u64) {
+        if self.cache.capacity.swap(capacity, Ordering::Relaxed) != capacity {
+            log::info!("read cache capacity set to {} bytes", capacity);
+            self.cache.evict();
+        }
+    }
+}
+
+impl<A: Access> Layer<A> for CacheLayer {
+    type LayeredAccess = CacheAccess<A>;
+
+    fn layer(&self, access: A) -> Self::LayeredAccess {
+        CacheAccess { access, cache: self.cache.clone() }
+    }
+}
+
+/// Identifies the backend version of a cached file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Version {
+    etag: Option<String>,
+    last_modified: Option<DateTime<Utc>>,
+    size: u64,
+}
+
+impl Version {
+    /// `None` when the metadata can't tell versions apart.
+    fn of(meta: &Metadata) -> Option<Self> {
+        if meta.etag().is_none() && meta.last_modified().is_none() {
+            return None;
+        }
+        Some(Version {
+            etag: meta.etag().map(str::to_string),
+            last_modified: meta.last_modified(),
+            size: meta.content_length(),
+        })
+    }
+
+    fn matches(&self, other: &Version) -> bool {
+        match (&self.etag, &other.etag) {
+            (Some(a), Some(b)) => a == b,
+            _ => self == other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    id: String,
+    path: String,
+    version: Version,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, (CacheEntry, u64)>,
+    /// recency tick to path, lowest is evicted first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+impl CacheState {
+    fn insert(&mut self, dir: &std::path::Path, entry: CacheEntry) {
+        self.remove(dir, &entry.path);
+        self.tick += 1;
+        self.size += entry.version.size;
+        self.lru.insert(self.tick, entry.path.clone());
+        self.entries.insert(entry.path.clone(), (entry, self.tick));
+    }
+
+    fn remove(&mut self, dir: &std::path::Path, path: &str) -> bool {
+        let Some((entry, tick)) = self.entries.remove(path) else { return false };
+        self.lru.remove(&tick);
+        self.size -= entry.version.size;
+        remove_entry_files(dir, &entry.id);
+        true
+    }
+
+    fn touch(&mut self, path: &str) {
+        if let Some((_, tick)) = self.entries.get_mut(path) {
+            self.lru.remove(tick);
+            self.tick += 1;
+            *tick = self.tick;
+            self.lru.insert(self.tick, path.to_string());
+        }
+    }
+}
+
+struct Cache {
+    dir: PathBuf,
+    capacity: AtomicU64,
+    state: Mutex<CacheState>,
+}
+
+fn remove_entry_files(dir: &std::path::Path, id: &str) {
+    let _ = std::fs::remove_file(dir.join(format!("{}.json", id)));
+    let _ = std::fs::remove_file(dir.join(format!("{}.data", id)));
+}
+
+impl Cache {
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.data", id))
+    }
+
+    fn invalidate(&self, path: &str) {
+        if self.state.lock().unwrap().remove(&self.dir, path) {
+            log::debug!("cache invalidated {}", path);
+        }
+    }
+
+    /// The cached entry of `path` if it is still at `version`.
+    fn lookup(&self, path: &str, version: &Version) -> Option<CacheEntry> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.entries.get(path).map(|(e, _)| e.clone())?;
+        if !entry.version.matches(version) {
+            log::debug!("cache stale {}", path);
+            state.remove(&self.dir, path);
+            return None;
+        }
+        state.touch(path);
+        Some(entry)
+    }
+
+    /// Open the data file `id` for a hit, marking it used for the lru order
+    /// the next start rebuilds.
+    async fn open_data_file(&self, id: &str) -> Result<File> {
+        let path = self.data_path(id);
+        let file = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(&path)?;
+            if let Err(e) = file.set_modified(SystemTime::now()) {
+                log::debug!("failed to touch {}: {}", path.display(), e);
+            }
+            Ok(file)
+        })
+            .await
+            .map_err(|e| Error::new(ErrorKind::Unexpected, "cache task failed").set_source(e))?
+            .map_err(io_error)?;
+        Ok(File::from_std(file))
+    }
+
+    async fn create_data_file(&self) -> Result<(String, File)> {
+        let dir = self.dir.clone();
+        let (file, path) = tokio::task::spawn_blocking(move || {
+            tempfile::Builder::new().prefix("cache-").suffix(".data").tempfile_in(dir)?.keep().map_err(|e| e.error)
+        })
+            .await
+            .map_err(|e| Error::new(ErrorKind::Unexpected, "cache task failed").set_source(e))?
+            .map_err(io_error)?;
+        let id = path.file_stem().and_then(|s| s.to_str()).expect("cache file name is utf-8").to_string();
+        Ok((id, File::from_std(file)))
+    }
+
+    async fn commit(&self, entry: CacheEntry) -> Result<()> {
+        let json = serde_json::to_vec(&entry).map_err(|e| Error::new(ErrorKind::Unexpected, "failed to serialize cache entry").set_source(e))?;
+        tokio::fs::write(self.dir.join(format!("{}.json", entry.id)), json).await.map_err(io_error)?;
+        log::debug!("cached {} ({} bytes)", entry.path, entry.version.size);
+        self.state.lock().unwrap().insert(&self.dir, entry);
+        self.evict();
+        Ok(())
+    }
+
+    fn evict(&self) {
+        let mut state = self.state.lock().unwrap();
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        while state.size > capacity {
+            let Some(path) = state.lru.values().next().cloned() else { break };
+            log::debug!("cache evict {}", path);
+            state.remove(&self.dir, &path);
+        }
+    }
+}
+
+pub struct CacheAccess<A> where A: Access {
+    access: A,
+    cache: Arc<Cache>,
+}
+
+impl<A: Access> std::fmt::Debug for CacheAccess<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CacheAccess").field("access", &self.access).finish()
+    }
+}
+
+impl<A: Access> LayeredAccess for CacheAccess<A> {
+    type Inner = A;
+    type Reader = oio::Reader;
+    type Writer = CacheInvalidateWriter<A::Writer>;
+    type Lister = A::Lister;
+    type Deleter = CacheDeleter<A::Deleter>;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.access
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+        let version = self.access.stat(path, OpStat::new()).await
+            .ok()
+            .and_then(|rp| Version::of(&rp.into_metadata()));
+        let Some(version) = version else {
+            let (rp, reader) = self.access.read(path, args).await?;
+            return Ok((rp, Box::new(reader)));
+        };
+        let range = args.range();
+        if let Some(entry) = self.cache.lookup(path, &version) {
+            log::debug!("cache hit {}", path);
+            let mut file = self.cache.open_data_file(&entry.id).await?;
+            file.seek(std::io::SeekFrom::Start(range.offset())).await.map_err(io_error)?;
+            let remaining = range.size().unwrap_or(u64::MAX).min(version.size.saturating_sub(range.offset()));
+            return Ok((RpRead::new().with_size(Some(remaining)), Box::new(CachedReader { file, remaining })));
+        }
+        let (rp, reader) = self.access.read(path, args).await?;
+        // only whole files that fit are worth keeping, readers stat first
+        // and ask for the exact range
+        let whole = range.offset() == 0 && range.size().is_none_or(|size| size >= version.size);
+        if !whole || version.size > self.cache.capacity.load(Ordering::Relaxed) {
+            return Ok((rp, Box::new(reader)));
+        }
+        let (id, file) = self.cache.create_data_file().await?;
+        Ok((rp, Box::new(CacheFillReader {
+            inner: reader,
+            cache: self.cache.clone(),
+            file: Some(file),
+            entry: CacheEntry { id, path: path.to_string(), version },
+            written: 0,
+        })))
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+        self.cache.invalidate(path);
+        let (rp, writer) = self.access.write(path, args).await?;
+        Ok((rp, CacheInvalidateWriter { inner: writer, cache: self.cache.clone(), path: path.to_string() }))
+    }
+
+    async fn copy(&self, from: &str, to: &str, args: OpCopy) -> Result<RpCopy> {
+        self.cache.invalidate(to);
+        self.access.copy(from, to, args).await
+    }
+
+    async fn rename(&self, from: &str, to: &str, args: OpRename) -> Result<RpRename> {
+        self.cache.invalidate(from);
+        self.cache.invalidate(to);
+        self.access.rename(from, to, args).await
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Lister)> {
+        self.access.list(path, args).await
+    }
+
+    async fn delete(&self) -> Result<(RpDelete, Self::Deleter)> {
+        let (rp, deleter) = self.access.delete().await?;
+        Ok((rp, CacheDeleter { inner: deleter, cache: self.cache.clone() }))
+    }
+}
+
+pub struct CachedReader {
+    file: File,
+    remaining: u64,
+}
+
+impl oio::Read for CachedReader {
+    async fn read(&mut self) -> Result<Buffer> {
+        let len = (READ_CHUNK as u64).min(self.remaining) as usize;
+        let mut chunk = BytesMut::with_capacity(len);
+        while chunk.len() < len {
+            if self.file.read_buf(&mut chunk).await.map_err(io_error)? == 0 {
+                break;
+            }
+        }
+        self.remaining -= chunk.len() as u64;
+        Ok(chunk.freeze().into())
+    }
+}
+
+/// Passes reads through while copying them into a cache file, which is
+/// committed once the whole file has been read.
+pub struct CacheFillReader<R> {
+    inner: R,
+    cache: Arc<Cache>,
+    file: Option<File>,
+    entry: CacheEntry,
+    written: u64,
+}
+
+impl<R> CacheFillReader<R> {
+    fn give_up(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(self.cache.data_path(&self.entry.id));
+        }
+    }
+}
+
+impl<R> CacheFillReader<R> {
+    /// Commit the cache file if the whole file made it in, drop it
+    /// otherwise.
+    async fn finish(&mut self) {
+        let Some(file) = self.file.take() else { return };
+        let complete = self.written == self.entry.version.size;
+        // durable before the json vouches for it
+        match (complete, file.sync_all().await) {
+            (true, Ok(())) => {
+                if let Err(e) = self.cache.commit(self.entry.clone()).await {
+                    log::warn!("failed to cache {}: {}", self.entry.path, e);
+                    let _ = std::fs::remove_file(self.cache.data_path(&self.entry.id));
+                }
+            }
+            _ => {
+                let _ = std::fs::remove_file(self.cache.data_path(&self.entry.id));
+            }
+        }
+    }
+}
+
+impl<R: oio::Read> oio::Read for CacheFillReader<R> {
+    async fn read(&mut self) -> Result<Buffer> {
+        let bs = self.inner.read().await?;
+        let Some(file) = self.file.as_mut() else { return Ok(bs) };
+        if bs.is_empty() {
+            self.finish().await;
+            return Ok(bs);
+        }
+        let mut failed = false;
+        for chunk in bs.clone() {
+            if let Err(e) = file.write_all(&chunk).await {
+                log::warn!("failed to write cache file for {}: {}", self.entry.path, e);
+                failed = true;
+                break;
+            }
+            self.written += chunk.len() as u64;
+        }
+        if failed {
+            self.give_up();
+        } else if self.written >= self.entry.version.size {
+            // readers asking for the exact range stop without an empty read
+            self.finish().await;
+        }
+        Ok(bs)
+    }
+}
+
+impl<R> Drop for CacheFillReader<R> {
+    fn drop(&mut self) {
+        self.give_up();
+    }
+}
+
+pub struct CacheInvalidateWriter<W> {
+    inner: W,
+    cache: Arc<Cache>,
+    path: String,
+}
+
+impl<W: oio::Write> oio::Write for CacheInvalidateWriter<W> {
+    async fn write(&mut self, bs: Buffer) -> Result<()> {
+        self.inner.write(bs).await
+    }
+
+    async fn close(&mut self) -> Result<Metadata> {
+        // a read racing with the write may have cached the old content
+        self.cache.invalidate(&self.path);
+        self.inner.close().await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.inner.abort().await
+    }
+}
+
+pub struct CacheDeleter<D> {
+    inner: D,
+    cache: Arc<Cache>,
+}
+
+impl<D: oio::Delete> oio::Delete for CacheDeleter<D> {
+    fn delete(&mut self, path: &str, args: OpDelete) -> Result<()> {
+        self.cache.invalidate(path);
+        self.inner.delete(path, args)
+    }
+
+    async fn flush(&mut self) -> Result<usize> {
+        self.inner.flush().await
+    }
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Unexpected, "read cache io failed").set_source(e)
+}
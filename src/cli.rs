@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use oauth2::url::Url;
+
+use crate::basic_auth::Users;
+use crate::config::{Config, MountConfig};
+use crate::odrive::{ODriveSession, SessionStatus};
+use crate::types::Response;
+
+/// WebDAV server for Zotero, backed by OneDrive and other storages.
+#[derive(Parser, Debug)]
+#[command(version = crate::GIT_REVISION)]
+pub struct Cli {
+    /// toml config file, the env vars describe the accounts without one
+    #[arg(long, global = true, env = "PAPERFS_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the server, the default without a command
+    Serve,
+    /// Sign an account in and save its tokens, a running server picks them
+    /// up on restart
+    Login {
+        /// needed with several accounts
+        account: Option<String>,
+        /// sign in with a browser and paste the address it was redirected
+        /// to, instead of entering a device code
+        #[arg(long)]
+        browser: bool,
+    },
+    /// Show the accounts' sign in, token expiry and last refresh
+    Status {
+        /// all accounts without one
+        account: Option<String>,
+        /// the running server to ask, defaults to the bind address
+        #[arg(long)]
+        server: Option<Url>,
+    },
+    /// Validate the config and check the backend root of each mount is
+    /// reachable
+    CheckConfig,
+    /// Refresh the accounts' access tokens now
+    Refresh {
+        /// all accounts without one
+        account: Option<String>,
+        /// the running server to ask, defaults to the bind address
+        #[arg(long)]
+        server: Option<Url>,
+        /// the dav user to ask the server as, with the password from
+        /// `PAPERFS_PASSWORD` or stdin
+        #[arg(long, env = "PAPERFS_USER")]
+        user: Option<String>,
+    },
+    /// Hash a password read from stdin with argon2, for the config's dav users
+    HashPassword,
+}
+
+/// Signs `account` in and saves its tokens in its token store.
+pub async fn login(config: &Config, account: Option<String>, browser: bool) -> Result<()> {
+    let name = match account {
+        Some(name) => name,
+        None if config.accounts.len() == 1 => config.accounts.keys().next().unwrap().clone(),
+        None => anyhow::bail!("which account? one of: {}", config.accounts.keys().cloned().collect::<Vec<_>>().join(", ")),
+    };
+    let session = session(config, &name)?;
+    if session.app_only() {
+        session.refresh().await?;
+        println!("App-only token acquired, tokens saved");
+        return Ok(());
+    }
+    match browser || !session.has_device_auth() {
+        true => browser_login(&session).await,
+        false => device_login(&session).await,
+    }
+}
+
+pub async fn device_login(session: &ODriveSession) -> Result<()> {
+    let device_auth = session.initiate_device_auth().await?;
+    println!(
+        "To sign in, open {} and enter the code {} (expires in {} minutes)",
+        device_auth.verification_uri(),
+        device_auth.user_code(),
+        device_auth.expires_in().as_secs() / 60,
+    );
+    session.device_auth(&device_auth).await?;
+    println!("Signed in, tokens saved");
+    Ok(())
+}
+
+/// The authorization code flow without the server: the code is taken
+/// from the callback address the browser ends up at, whether or not
+/// anything answers there.
+async fn browser_login(session: &ODriveSession) -> Result<()> {
+    let url = session.initiate_auth().await;
+    println!("To sign in, open\n\n  {}\n\nthen paste the address the browser was redirected to:", url);
+    let line = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|_| line)
+    }).await??;
+    let redirect = Url::parse(line.trim()).context("invalid address")?;
+    let query: BTreeMap<_, _> = redirect.query_pairs().collect();
+    if let Some(error) = query.get("error") {
+        anyhow::bail!("sign in failed: {}", query.get("error_description").unwrap_or(error));
+    }
+    let (Some(code), Some(state)) = (query.get("code"), query.get("state")) else {
+        anyhow::bail!("no authorization code in the address");
+    };
+    session.auth(state.to_string(), code.to_string()).await?;
+    println!("Signed in, tokens saved");
+    Ok(())
+}
+
+/// Prints the status of the accounts, as the running server sees them or
+/// else from their token stores.
+pub async fn status(config: &Config, account: Option<String>, server: Option<Url>) -> Result<()> {
+    let server = server_url(config, server)?;
+    for name in account_names(config, account)? {
+        let url = server.join(&format!("{}/status", config.api_prefix(&name).trim_start_matches('/')))?;
+        match ask_server(reqwest::Client::new().get(url)).await? {
+            Some(resp) => print_status(&name, &resp.body, "server"),
+            None => {
+                let session = session(config, &name)?;
+                session.load_token().await?;
+                print_status(&name, &session.status().await, "token store");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Refreshes the access tokens through the running server, or else right
+/// in the token stores.
+pub async fn refresh(config: &Config, account: Option<String>, server: Option<Url>, user: Option<String>) -> Result<()> {
+    let server = server_url(config, server)?;
+    let credentials = match user {
+        Some(user) => Some((user, read_password()?)),
+        None => None,
+    };
+    let mut failed = Vec::new();
+    for name in account_names(config, account)? {
+        let url = server.join(&format!("{}/refresh", config.api_prefix(&name).trim_start_matches('/')))?;
+        let mut request = reqwest::Client::new().post(url);
+        if let Some((user, password)) = credentials.as_ref() {
+            request = request.basic_auth(user, Some(password));
+        }
+        let (res, status, source) = match ask_server(request).await? {
+            Some(resp) if resp.code == http::StatusCode::OK.as_u16() => (Ok(()), resp.body, "server"),
+            Some(resp) => (Err(anyhow::anyhow!(resp.msg)), resp.body, "server"),
+            None => {
+                let session = session(config, &name)?;
+                session.load_token().await?;
+                (session.refresh().await, session.status().await, "token store")
+            }
+        };
+        if let Err(e) = res {
+            eprintln!("error: {}: {:#}", name, e);
+            failed.push(name.clone());
+        }
+        print_status(&name, &status, source);
+    }
+    anyhow::ensure!(failed.is_empty(), "refresh failed for {}", failed.join(", "));
+    Ok(())
+}
+
+/// Builds every mount's backend and checks its root is reachable, signed
+/// in with the saved tokens where the backend needs them.
+pub async fn check_config(config: &Config) -> Result<()> {
+    println!("config ok: {} accounts, {} mounts", config.accounts.len(), config.mounts.len());
+    match Users::from_config(&config.auth)? {
+        Some(users) => println!("dav users: {}", users.len()),
+        None => println!("dav users: none, the mounts are open to anyone"),
+    }
+    let mut sessions = BTreeMap::new();
+    for name in config.accounts.keys() {
+        let session = session(config, name)?;
+        session.load_token().await?;
+        // only when expired, the running server keeps it fresh otherwise
+        if !session.status().await.healthy() {
+            if let Err(e) = session.refresh().await {
+                println!("account {}: {:#}", name, e);
+            }
+        }
+        sessions.insert(name.clone(), session);
+    }
+    let mut failed = 0;
+    for mount in config.mounts.iter() {
+        let res = reachable(mount, &sessions).await;
+        match res {
+            Ok(()) => println!("{}: ok, {} reachable", mount.prefix, mount.root),
+            Err(e) => {
+                println!("{}: {:#}", mount.prefix, e);
+                failed += 1;
+            }
+        }
+    }
+    anyhow::ensure!(failed == 0, "{} of {} mounts unreachable", failed, config.mounts.len());
+    Ok(())
+}
+
+/// Stats the mount's root from the backend's top, opendal answers a stat
+/// of its own root without asking the backend and lists a missing dir as
+/// empty.
+async fn reachable(mount: &MountConfig, sessions: &BTreeMap<String, ODriveSession>) -> Result<()> {
+    let root = mount.root.trim_matches('/');
+    let top = MountConfig { root: "/".to_string(), ..mount.clone() };
+    let (op, _) = crate::mount_backend(&top, sessions)?;
+    if !root.is_empty() {
+        let meta = op.stat(&format!("{}/", root)).await?;
+        anyhow::ensure!(meta.is_dir(), "{} is not a directory", mount.root);
+    }
+    op.check().await?;
+    Ok(())
+}
+
+pub fn hash_password() -> Result<()> {
+    let password = read_line()?;
+    anyhow::ensure!(!password.is_empty(), "empty password");
+    println!("{}", crate::basic_auth::hash_password(&password)?);
+    Ok(())
+}
+
+fn read_password() -> Result<String> {
+    match std::env::var("PAPERFS_PASSWORD") {
+        Ok(password) => Ok(password),
+        Err(_) => read_line(),
+    }
+}
+
+fn read_line() -> Result<String> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn session(config: &Config, name: &str) -> Result<ODriveSession> {
+    let account = config.accounts.get(name).with_context(|| format!("unknown account {}", name))?;
+    crate::account_session(name, account, config.redirect_url(name))
+}
+
+fn account_names(config: &Config, account: Option<String>) -> Result<Vec<String>> {
+    match account {
+        Some(name) if config.accounts.contains_key(&name) => Ok(vec![name]),
+        Some(name) => anyhow::bail!("unknown account {}", name),
+        None => Ok(config.accounts.keys().cloned().collect()),
+    }
+}
+
+/// `--server`, or the bind address with a wildcard ip made local.
+fn server_url(config: &Config, server: Option<Url>) -> Result<Url> {
+    if let Some(server) = server {
+        return Ok(server);
+    }
+    let mut addr: SocketAddr = config.server.bind_addr.parse().context("invalid bind address")?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    Ok(Url::parse(&format!("http://{}/", addr))?)
+}
+
+/// `None` when no server is listening.
+async fn ask_server(request: reqwest::RequestBuilder) -> Result<Option<Response<SessionStatus>>> {
+    match request.timeout(Duration::from_secs(30)).send().await {
+        // a failed refresh still comes with the status
+        Ok(resp) if resp.status() == http::StatusCode::UNAUTHORIZED => {
+            anyhow::bail!("the server wants a dav user and their password, see --user")
+        }
+        Ok(resp) => {
+            let code = resp.status();
+            Ok(Some(resp.json().await.with_context(|| format!("unexpected server response: {}", code))?))
+        }
+        Err(e) if e.is_connect() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn print_status(name: &str, status: &SessionStatus, source: &str) {
+    println!("account {} (from the {}):", name, source);
+    println!("  signed in:     {}", if status.signed_in { "yes" } else { "no" });
+    if let Some(reason) = status.relogin_required.as_ref() {
+        println!("  re-login required: {}", reason);
+    }
+    if let Some(at) = status.expires_at {
+        let left = at as i64 - chrono::Utc::now().timestamp();
+        match left > 0 {
+            true => println!("  token expires: {} (in {} min)", fmt_time(at), left / 60),
+            false => println!("  token expired: {}", fmt_time(at)),
+        }
+    }
+    let refresh = &status.refresh;
+    match (refresh.last_error.as_ref(), refresh.last_success) {
+        (Some(e), _) => println!("  last refresh:  failed ({} in a row): {}", refresh.consecutive_failures, e),
+        (None, Some(at)) => println!("  last refresh:  ok at {}", fmt_time(at)),
+        // only the server keeps a refresh history
+        (None, None) => println!("  last refresh:  none"),
+    }
+    if let Some(at) = refresh.next_refresh {
+        println!("  next refresh:  {}", fmt_time(at));
+    }
+}
+
+fn fmt_time(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| secs.to_string())
+}
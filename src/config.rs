@@ -0,0 +1,403 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::account::AccountEnv;
+use crate::odrive::Provider;
+
+/// Everything the server is built from, read from the toml file in
+/// `PAPERFS_CONFIG`, or from the env vars without one.
+///
+/// ```toml
+/// [server]
+/// exposed_url = "https://paperfs.example.com"
+///
+/// [accounts.alice]
+/// client_id = "..."
+///
+/// [[auth.users]]
+/// name = "alice"
+/// password_hash = "$argon2id$v=19$..."
+///
+/// [[mounts]]
+/// prefix = "/zotero"
+/// root = "/zotero"
+/// backend = { type = "onedrive", account = "alice" }
+/// layers = [{ type = "buffer" }, { type = "meta_cache", ttl = 30 }, { type = "mux" }]
+/// ```
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// oauth sessions by name, the mounts refer to them
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountConfig>,
+    #[serde(default)]
+    pub mounts: Vec<MountConfig>,
+    /// who may use the dav mounts, anyone without users
+    #[serde(default)]
+    pub auth: AuthConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(default = "default_bind_addr")]
+    pub bind_addr: String,
+    /// public base url, the oauth callbacks are under it
+    #[serde(default = "default_exposed_url")]
+    pub exposed_url: String,
+    /// largest dav request body accepted by the mounts without their own
+    pub max_body_size: Option<u64>,
+    /// shared by all mounts, locks are kept by their full url path
+    #[serde(default = "default_lock_file")]
+    pub lock_file: PathBuf,
+    /// tracing filter, e.g. `info,paperfs_rs=debug`, `RUST_LOG` overrides it
+    pub log_level: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addr: default_bind_addr(),
+            exposed_url: default_exposed_url(),
+            max_body_size: None,
+            lock_file: default_lock_file(),
+            log_level: None,
+        }
+    }
+}
+
+fn default_bind_addr() -> String {
+    "0.0.0.0:3000".to_string()
+}
+
+fn default_exposed_url() -> String {
+    "http://localhost:3000".to_string()
+}
+
+fn default_lock_file() -> PathBuf {
+    PathBuf::from("dav_locks.json")
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// shown by clients asking for the password, `paperfs` by default
+    pub realm: Option<String>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// more users, as `name:hash` lines like `htpasswd -B` writes them
+    pub htpasswd_file: Option<PathBuf>,
+    /// where the users' app passwords are kept, hashed,
+    /// `app_passwords.json` by default
+    pub app_passwords_file: Option<PathBuf>,
+}
+
+impl AuthConfig {
+    pub fn app_passwords_file(&self) -> PathBuf {
+        self.app_passwords_file.clone().unwrap_or_else(|| PathBuf::from("app_passwords.json"))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    /// argon2 (PHC string) or bcrypt
+    pub password_hash: String,
+}
+
+/// An oauth session: provider, app registration, drive and where its
+/// tokens are kept.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    #[serde(default)]
+    pub provider: Provider,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub revocation_url: Option<String>,
+    pub authority: Option<String>,
+    pub tenant: Option<String>,
+    pub graph_url: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    #[serde(default)]
+    pub app_folder: bool,
+    #[serde(default)]
+    pub app_only: bool,
+    /// certificate private key (pem) for app-only auth
+    pub client_cert_key_file: Option<PathBuf>,
+    pub client_cert_thumbprint: Option<String>,
+    /// another user's drive, by id or user principal name
+    pub drive_user: Option<String>,
+    /// any drive by id, e.g. a SharePoint document library
+    pub drive_id: Option<String>,
+    #[serde(default)]
+    pub token_store: TokenStoreKind,
+    /// defaults to `app_data.<account>.json`
+    pub token_file: Option<PathBuf>,
+    /// encrypts the token file, either inline or in a file
+    pub token_key: Option<String>,
+    pub token_key_file: Option<PathBuf>,
+    /// read-only provisioned credential, takes precedence over the token store
+    pub refresh_token_file: Option<PathBuf>,
+    /// seconds before expiry to refresh the access token
+    pub refresh_margin: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStoreKind {
+    #[default]
+    File,
+    Memory,
+}
+
+/// A dav service under `prefix`, serving `root` of its backend through
+/// `layers`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    pub prefix: String,
+    pub backend: BackendConfig,
+    #[serde(default = "default_root")]
+    pub root: String,
+    /// largest dav request body accepted, the server's if unset
+    pub max_body_size: Option<u64>,
+    /// applied in order, the first one wraps the backend
+    #[serde(default = "default_layers")]
+    pub layers: Vec<LayerConfig>,
+}
+
+impl MountConfig {
+    /// Equal but for what a reload changes in place, the cache sizes and
+    /// mux files.
+    pub fn retunable_to(&self, other: &MountConfig) -> bool {
+        let untuned = |mount: &MountConfig| MountConfig {
+            layers: mount.layers.iter().map(|layer| match layer {
+                LayerConfig::Cache { dir, .. } => LayerConfig::Cache { dir: dir.clone(), size: None },
+                LayerConfig::Mux { .. } => LayerConfig::Mux { files: Vec::new() },
+                other => other.clone(),
+            }).collect(),
+            ..mount.clone()
+        };
+        untuned(self) == untuned(other)
+    }
+}
+
+fn default_root() -> String {
+    "/".to_string()
+}
+
+fn default_layers() -> Vec<LayerConfig> {
+    vec![
+        LayerConfig::Buffer { mem_threshold: None, spill_dir: None },
+        LayerConfig::Mux { files: default_mux_files() },
+    ]
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BackendConfig {
+    /// a OneDrive account's drive, served once the account is signed in
+    Onedrive { account: String },
+    /// a Google account's drive, served once the account is signed in
+    Gdrive { account: String },
+    /// a Dropbox account, served once the account is signed in
+    Dropbox { account: String },
+    /// a local dir, the mount's root
+    Fs,
+    Memory,
+    /// an S3 compatible bucket
+    S3 {
+        bucket: String,
+        endpoint: Option<String>,
+        region: Option<String>,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+    },
+    /// an upstream WebDAV server
+    Webdav {
+        endpoint: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// any opendal service compiled in, configured with its options
+    Service {
+        scheme: String,
+        #[serde(default)]
+        options: BTreeMap<String, String>,
+    },
+}
+
+impl BackendConfig {
+    /// The account whose session signs the backend's requests.
+    pub fn account(&self) -> Option<&str> {
+        match self {
+            BackendConfig::Onedrive { account } | BackendConfig::Gdrive { account } | BackendConfig::Dropbox { account } => Some(account),
+            _ => None,
+        }
+    }
+
+    fn provider(&self) -> Option<Provider> {
+        match self {
+            BackendConfig::Onedrive { .. } => Some(Provider::Microsoft),
+            BackendConfig::Gdrive { .. } => Some(Provider::Google),
+            BackendConfig::Dropbox { .. } => Some(Provider::Dropbox),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LayerConfig {
+    /// collects writes before handing them to the backend
+    Buffer {
+        mem_threshold: Option<usize>,
+        spill_dir: Option<PathBuf>,
+    },
+    /// resumable OneDrive upload sessions, replaces the buffer
+    Upload {
+        dir: PathBuf,
+        chunk_size: Option<usize>,
+    },
+    /// write-back journal
+    Journal { dir: PathBuf },
+    /// local read cache
+    Cache {
+        dir: PathBuf,
+        size: Option<u64>,
+    },
+    /// stat/list cache, ttl in seconds
+    MetaCache { ttl: u64 },
+    /// keeps files matching `files` in memory, macOS metadata by default
+    Mux {
+        #[serde(default = "default_mux_files")]
+        files: Vec<String>,
+    },
+}
+
+impl LayerConfig {
+    /// The dir the layer keeps its state in, one layer of one mount each.
+    fn state_dir(&self) -> Option<&Path> {
+        match self {
+            LayerConfig::Upload { dir, .. } | LayerConfig::Journal { dir } | LayerConfig::Cache { dir, .. } => Some(dir),
+            _ => None,
+        }
+    }
+}
+
+pub fn default_mux_files() -> Vec<String> {
+    vec!["._*".to_string(), "*DS_Store".to_string()]
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let config: Config = toml::from_str(&data)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The config the env vars describe: one mount per account, see
+    /// [`AccountEnv`].
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        if let Ok(bind_addr) = std::env::var("PAPERFS_BIND_ADDR") {
+            config.server.bind_addr = bind_addr;
+        }
+        if let Ok(exposed_url) = std::env::var("PAPERFS_EXPOSED_URL") {
+            config.server.exposed_url = exposed_url;
+        }
+        config.server.max_body_size = std::env::var("PAPERFS_DAV_MAX_BODY_SIZE").ok()
+            .map(|s| s.parse::<u64>()).transpose().context("invalid PAPERFS_DAV_MAX_BODY_SIZE")?;
+        if let Ok(lock_file) = std::env::var("PAPERFS_LOCK_FILE") {
+            config.server.lock_file = PathBuf::from(lock_file);
+        }
+        config.auth.realm = std::env::var("PAPERFS_AUTH_REALM").ok();
+        config.auth.htpasswd_file = std::env::var("PAPERFS_HTPASSWD_FILE").ok().map(PathBuf::from);
+        config.auth.app_passwords_file = std::env::var("PAPERFS_APP_PASSWORDS_FILE").ok().map(PathBuf::from);
+        for account in AccountEnv::from_env()? {
+            config.accounts.insert(account.name().to_string(), account.config()?);
+            config.mounts.push(account.mount()?);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(level) = self.server.log_level.as_ref() {
+            tracing_subscriber::EnvFilter::try_new(level).with_context(|| format!("invalid log_level {:?}", level))?;
+        }
+        if let Some(realm) = self.auth.realm.as_ref() {
+            anyhow::ensure!(realm.chars().all(|c| c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\')), "invalid auth realm {:?}", realm);
+        }
+        for (i, user) in self.auth.users.iter().enumerate() {
+            crate::basic_auth::check_user(&user.name, &user.password_hash)?;
+            anyhow::ensure!(!self.auth.users[..i].iter().any(|u| u.name == user.name), "duplicate user {}", user.name);
+        }
+        for (name, account) in self.accounts.iter() {
+            anyhow::ensure!(!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "invalid account name {:?}", name);
+            anyhow::ensure!(!(account.drive_user.is_some() && account.drive_id.is_some()),
+                "account {}: drive_user and drive_id are exclusive", name);
+            // scopes would widen the app folder's
+            anyhow::ensure!(!(account.app_folder && account.scopes.is_some()),
+                "account {}: app_folder sets its own scopes, drop scopes", name);
+        }
+        // their state is keyed by the path within the mount, a shared dir
+        // would mix up the mounts' files
+        let mut state_dirs: Vec<(&Path, &str)> = Vec::new();
+        for (i, mount) in self.mounts.iter().enumerate() {
+            let prefix = mount.prefix.as_str();
+            anyhow::ensure!(prefix.starts_with('/') && prefix.len() > 1 && !prefix.ends_with('/'),
+                "mount prefix {:?} must start with / and not end with one", prefix);
+            anyhow::ensure!(prefix != "/api" && !prefix.starts_with("/api/"), "mount prefix {} is taken by the api", prefix);
+            anyhow::ensure!(!self.mounts[..i].iter().any(|m| m.prefix == mount.prefix), "duplicate mount prefix {}", prefix);
+            if let (Some(name), Some(provider)) = (mount.backend.account(), mount.backend.provider()) {
+                let account = self.accounts.get(name)
+                    .with_context(|| format!("mount {}: unknown account {}", prefix, name))?;
+                anyhow::ensure!(account.provider == provider, "mount {}: account {} isn't a {} account", prefix, name, provider);
+            }
+            if !matches!(mount.backend, BackendConfig::Onedrive { .. }) && mount.layers.iter().any(|l| matches!(l, LayerConfig::Upload { .. })) {
+                anyhow::bail!("mount {}: upload sessions only work with onedrive", prefix);
+            }
+            for dir in mount.layers.iter().filter_map(LayerConfig::state_dir) {
+                if let Some((_, other)) = state_dirs.iter().find(|(d, _)| *d == dir) {
+                    anyhow::bail!("mount {}: {} is already used by {}, each layer needs its own dir", prefix, dir.display(), other);
+                }
+                state_dirs.push((dir, prefix));
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the login endpoints of the account `name` are nested, a lone
+    /// account keeps the unnamed paths.
+    pub fn api_prefix(&self, name: &str) -> String {
+        match self.accounts.len() {
+            1 => "/api/v1/onedrive".to_string(),
+            _ => format!("/api/v1/onedrive/{}", name),
+        }
+    }
+
+    /// The oauth callback of the account `name`.
+    pub fn redirect_url(&self, name: &str) -> String {
+        format!("{}{}/callback", self.server.exposed_url, self.api_prefix(name))
+    }
+
+    /// Mount prefixes served by the account `name`.
+    pub fn account_mounts(&self, name: &str) -> Vec<String> {
+        self.mounts.iter()
+            .filter(|m| m.backend.account() == Some(name))
+            .map(|m| m.prefix.clone())
+            .collect()
+    }
+}
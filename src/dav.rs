@@ -1,14 +1,18 @@
-use http::{Request, Uri};
+use http::{StatusCode, Uri};
+use http_body::{Frame, SizeHint};
 use tower::{Service, service_fn};
-use dav_server::DavHandler;
-use bytes::{Buf, BufMut};
+use dav_server::{DavConfig, DavHandler};
+use bytes::Buf;
 use std::convert::Infallible;
 use std::error::Error as StdError;
-use std::fmt::Debug;
-use std::future::{poll_fn, Future};
-use std::pin::{pin, Pin};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll};
 
+use crate::basic_auth::AuthUser;
+
 #[allow(dead_code)]
 pub fn into_service<B, D, E>(handler: DavHandler) -> impl Service<http::Request<B>> + Clone + Send + Sync + 'static where
     D: Buf + Send + 'static,
@@ -27,18 +31,26 @@ pub fn into_service<B, D, E>(handler: DavHandler) -> impl Service<http::Request<
 #[derive(Clone)]
 pub struct DavHandlerWrapper {
     inner: DavHandler,
+    max_body_size: Option<u64>,
 }
 
 impl DavHandlerWrapper {
     pub fn new(handler: DavHandler) -> Self {
         Self {
             inner: handler,
+            max_body_size: None,
         }
     }
+
+    /// Reject request bodies larger than `limit` bytes, `None` for unlimited.
+    pub fn max_body_size(mut self, limit: Option<u64>) -> Self {
+        self.max_body_size = limit;
+        self
+    }
 }
 
 impl<B, D, E> Service<http::Request<B>> for DavHandlerWrapper where
-    D: Buf + Send + Debug + 'static,
+    D: Buf + Send + 'static,
     E: StdError + Send + Sync + 'static,
     B: http_body::Body<Data=D, Error=E> + Send + 'static,
 {
@@ -59,36 +71,107 @@ impl<B, D, E> Service<http::Request<B>> for DavHandlerWrapper where
                 let mut builder = Uri::builder();
                 if let Some(scheme) = req.uri().scheme() { builder = builder.scheme(scheme.clone()); }
                 if let Some(authority) = req.uri().authority() { builder = builder.authority(authority.clone()); }
-                if let Some(_) = req.uri().path_and_query() {
+                if req.uri().path_and_query().is_some() {
                     let pnq = format!("{}/{}", req.uri().path(), req.uri().query().unwrap_or(""));
-                    builder = builder.path_and_query(pnq); 
+                    builder = builder.path_and_query(pnq);
                 }
                 *req.uri_mut() = builder.build().unwrap();
             }
             log::debug!("DAV patched MKCOL {}", req.uri());
         }
-        let inner = self.inner.clone();
-        let fut = async move {
-            let mut builder = Request::builder()
-                .method(req.method())
-                .uri(req.uri());
-            *builder.headers_mut().unwrap() = req.headers().clone();
-            let mut buf = req.body_mut().size_hint().exact().map(|sz| Vec::with_capacity(sz as usize)).unwrap_or_else(Vec::new);
-            let mut body = pin!(req.into_body());
-            while !body.is_end_stream() {
-                log::debug!("DAV poll frame");
-                if let Some(data) = poll_fn(|cx| body.as_mut().poll_frame(cx)).await {
-                    log::debug!("DAV frame: {:?}", data);
-                    buf.put(data.unwrap().into_data().unwrap());
-                }
+        if let Some(limit) = self.max_body_size {
+            let content_length = req.headers()
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            if let Some(len) = content_length.filter(|len| *len > limit) {
+                log::warn!("DAV {} {} rejected, body of {} bytes exceeds limit {}", req.method(), req.uri(), len, limit);
+                return Box::pin(async { Ok(payload_too_large()) });
             }
-            log::debug!("DAV body collected: {:?} bytes", buf.len());
-            match String::from_utf8(buf.clone()) {
-                Ok(s) => log::debug!("DAV body collected: {:}", s),
-                Err(err) => log::debug!("DAV body collected: {:?}", err),
+        }
+        let inner = self.inner.clone();
+        // locks belong to the authenticated user
+        let principal = req.extensions().get::<AuthUser>().map(|user| user.0.clone());
+        let (parts, body) = req.into_parts();
+        let body = LimitedBody::new(body, self.max_body_size);
+        let exceeded = body.exceeded.clone();
+        let req = http::Request::from_parts(parts, body);
+        Box::pin(async move {
+            let resp = match principal {
+                Some(principal) => inner.handle_with(DavConfig::new().principal(principal), req).await,
+                None => inner.handle(req).await,
+            };
+            // dav_server only sees a failed body, whatever it answers
+            if exceeded.load(Ordering::Relaxed) {
+                return Ok(payload_too_large());
             }
-            Ok(inner.handle(builder.body(axum::body::Body::from(buf)).unwrap()).await)
+            Ok(resp)
+        })
+    }
+}
+
+fn payload_too_large() -> http::Response<dav_server::body::Body> {
+    http::Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header("Content-Length", "0")
+        .body(dav_server::body::Body::empty())
+        .unwrap()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BodyError<E> {
+    #[error("request body exceeds {0} bytes")]
+    TooLarge(u64),
+    #[error(transparent)]
+    Inner(E),
+}
+
+/// Passes the request body through frame by frame, failing once more than
+/// `limit` bytes have been read. Covers chunked bodies without a `Content-Length`.
+pub struct LimitedBody<B> {
+    inner: Pin<Box<B>>,
+    limit: Option<u64>,
+    read: u64,
+    /// set once the limit is hit, for the response to be a 413
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<B> LimitedBody<B> {
+    fn new(inner: B, limit: Option<u64>) -> Self {
+        LimitedBody { inner: Box::pin(inner), limit, read: 0, exceeded: Arc::default() }
+    }
+}
+
+impl<B, D, E> http_body::Body for LimitedBody<B> where
+    D: Buf,
+    B: http_body::Body<Data=D, Error=E>,
+{
+    type Data = D;
+    type Error = BodyError<E>;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<D>, Self::Error>>> {
+        let frame = match self.inner.as_mut().poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(BodyError::Inner(e)))),
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
         };
-        Box::pin(fut)
+        if let Some(data) = frame.data_ref() {
+            self.read += data.remaining() as u64;
+            if let Some(limit) = self.limit.filter(|limit| self.read > *limit) {
+                log::warn!("DAV body exceeds limit {}", limit);
+                self.exceeded.store(true, Ordering::Relaxed);
+                return Poll::Ready(Some(Err(BodyError::TooLarge(limit))));
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
     }
 }
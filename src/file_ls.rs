@@ -0,0 +1,543 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use dav_server::davpath::DavPath;
+use dav_server::ls::{DavLock, DavLockSystem, LsFuture};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use xmltree::Element;
+
+/// WebDAV lock system persisted to a json file.
+///
+/// Same semantics as dav_server's `MemLs`, except locks survive restarts and
+/// expire once their timeout passes. Expired locks are dropped lazily, on the
+/// next operation that looks at them. Paths are kept with their mount prefix.
+#[derive(Clone)]
+pub struct FileLs {
+    inner: Arc<FileLsInner>,
+}
+
+struct FileLsInner {
+    path: PathBuf,
+    state: Mutex<LsState>,
+    /// version of the state last written to `path`
+    saved: tokio::sync::Mutex<u64>,
+}
+
+#[derive(Default)]
+struct LsState {
+    locks: Vec<DavLock>,
+    /// bumped on every change
+    version: u64,
+}
+
+impl std::fmt::Debug for FileLs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileLs").field("path", &self.inner.path).finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LockRecord {
+    token: String,
+    /// url encoded, with prefix
+    path: String,
+    prefix: String,
+    principal: Option<String>,
+    /// owner element as xml
+    owner: Option<String>,
+    timeout_at: Option<DateTime<Utc>>,
+    timeout: Option<u64>,
+    shared: bool,
+    deep: bool,
+}
+
+impl LockRecord {
+    fn from_lock(lock: &DavLock) -> Self {
+        LockRecord {
+            token: lock.token.clone(),
+            path: lock.path.with_prefix().as_url_string(),
+            prefix: lock.path.prefix().to_string(),
+            principal: lock.principal.clone(),
+            owner: lock.owner.as_ref().and_then(|owner| {
+                let mut xml = Vec::new();
+                owner.write(&mut xml).ok()?;
+                String::from_utf8(xml).ok()
+            }),
+            timeout_at: lock.timeout_at.map(DateTime::from),
+            timeout: lock.timeout.map(|d| d.as_secs()),
+            shared: lock.shared,
+            deep: lock.deep,
+        }
+    }
+
+    fn into_lock(self) -> anyhow::Result<DavLock> {
+        let mut path = DavPath::new(&self.path)
+            .map_err(|e| anyhow::anyhow!("invalid lock path {}: {:?}", self.path, e))?;
+        if !self.prefix.is_empty() {
+            path.set_prefix(&self.prefix)
+                .map_err(|e| anyhow::anyhow!("invalid lock prefix {}: {:?}", self.prefix, e))?;
+        }
+        let owner = match self.owner {
+            Some(xml) => Some(Element::parse(xml.as_bytes())?),
+            None => None,
+        };
+        Ok(DavLock {
+            token: self.token,
+            path,
+            principal: self.principal,
+            owner,
+            timeout_at: self.timeout_at.map(SystemTime::from),
+            timeout: self.timeout.map(Duration::from_secs),
+            shared: self.shared,
+            deep: self.deep,
+        })
+    }
+}
+
+/// A lock as shown by the admin api.
+#[derive(Debug, Serialize)]
+pub struct LockInfo {
+    pub token: String,
+    pub path: String,
+    pub principal: Option<String>,
+    pub owner: Option<String>,
+    pub timeout_at: Option<DateTime<Utc>>,
+    pub shared: bool,
+    pub deep: bool,
+}
+
+impl From<LockRecord> for LockInfo {
+    fn from(record: LockRecord) -> Self {
+        LockInfo {
+            token: record.token,
+            path: record.path,
+            principal: record.principal,
+            owner: record.owner,
+            timeout_at: record.timeout_at,
+            shared: record.shared,
+            deep: record.deep,
+        }
+    }
+}
+
+fn segs(path: &DavPath) -> Vec<&[u8]> {
+    path.with_prefix()
+        .as_bytes()
+        .split(|&c| c == b'/')
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// `a` is `b` or one of its ancestors.
+pub(crate) fn covers(a: &DavPath, b: &DavPath) -> bool {
+    let (a, b) = (segs(a), segs(b));
+    a.len() <= b.len() && a[..] == b[..a.len()]
+}
+
+fn expired(lock: &DavLock, now: SystemTime) -> bool {
+    lock.timeout_at.is_some_and(|at| at <= now)
+}
+
+impl LsState {
+    /// Drop expired locks, returns whether anything changed.
+    fn prune(&mut self) -> bool {
+        let now = SystemTime::now();
+        let before = self.locks.len();
+        self.locks.retain(|lock| !expired(lock, now));
+        let changed = self.locks.len() != before;
+        if changed {
+            self.version += 1;
+        }
+        changed
+    }
+
+    /// Conflicting lock on `path` or deep lock on one of its ancestors.
+    fn conflict_to_path(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        submitted_tokens: &[&str],
+        shared_ok: bool,
+    ) -> Option<&DavLock> {
+        let mut holds_lock = false;
+        let mut first_lock_seen: Option<&DavLock> = None;
+        for lock in self.locks.iter().filter(|l| covers(&l.path, path) && (l.deep || l.path == *path)) {
+            if submitted_tokens.iter().any(|t| lock.token == *t)
+                && (ignore_principal || principal == lock.principal.as_deref())
+            {
+                holds_lock = true;
+            } else {
+                if !lock.shared {
+                    return Some(lock);
+                }
+                if !shared_ok {
+                    first_lock_seen.get_or_insert(lock);
+                }
+            }
+        }
+        first_lock_seen.filter(|_| !holds_lock)
+    }
+
+    /// Conflicting lock on `path` or anything below it.
+    fn conflict_from_path(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        submitted_tokens: &[&str],
+        shared_ok: bool,
+    ) -> Option<&DavLock> {
+        self.locks.iter().filter(|l| covers(path, &l.path)).find(|lock| {
+            (!lock.shared || !shared_ok)
+                && (!submitted_tokens.iter().any(|t| lock.token == *t)
+                    || (!ignore_principal && principal != lock.principal.as_deref()))
+        })
+    }
+
+    /// Index of the lock `token` held on `path` or one of its ancestors.
+    fn lookup(&self, path: &DavPath, token: &str) -> Option<usize> {
+        self.locks.iter().position(|l| l.token == token && covers(&l.path, path))
+    }
+}
+
+impl FileLs {
+    /// Load the locks saved in `path`, starting empty if it doesn't exist.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut state = LsState::default();
+        match std::fs::read(&path) {
+            Ok(json) => {
+                let records: Vec<LockRecord> = serde_json::from_slice(&json)?;
+                for record in records {
+                    match record.into_lock() {
+                        Ok(lock) => state.locks.push(lock),
+                        Err(e) => log::warn!("dropping unreadable lock: {}", e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        state.prune();
+        log::info!("loaded {} dav locks from {}", state.locks.len(), path.display());
+        Ok(FileLs {
+            inner: Arc::new(FileLsInner {
+                path,
+                state: Mutex::new(state),
+                saved: tokio::sync::Mutex::new(0),
+            }),
+        })
+    }
+
+    /// All live locks.
+    pub async fn list(&self) -> Vec<LockInfo> {
+        let (locks, changed) = {
+            let mut state = self.inner.state.lock().unwrap();
+            let changed = state.prune();
+            (state.locks.iter().map(|l| LockRecord::from_lock(l).into()).collect(), changed)
+        };
+        if changed {
+            self.save().await;
+        }
+        locks
+    }
+
+    /// Force-break the lock `token`, returns whether it existed.
+    pub async fn break_lock(&self, token: &str) -> bool {
+        let found = {
+            let mut state = self.inner.state.lock().unwrap();
+            let before = state.locks.len();
+            state.locks.retain(|l| l.token != token);
+            let found = state.locks.len() != before;
+            if found {
+                state.version += 1;
+            }
+            found
+        };
+        if found {
+            log::info!("broke dav lock {}", token);
+            self.save().await;
+        }
+        found
+    }
+
+    /// Force-break every lock on `path` (url encoded, with prefix) and below,
+    /// returns how many were broken.
+    pub async fn break_path(&self, path: &str) -> anyhow::Result<usize> {
+        let path = DavPath::new(path).map_err(|e| anyhow::anyhow!("invalid path {}: {:?}", path, e))?;
+        let broken = {
+            let mut state = self.inner.state.lock().unwrap();
+            let before = state.locks.len();
+            state.locks.retain(|l| !covers(&path, &l.path));
+            let broken = before - state.locks.len();
+            if broken > 0 {
+                state.version += 1;
+            }
+            broken
+        };
+        if broken > 0 {
+            log::info!("broke {} dav locks under {}", broken, path);
+            self.save().await;
+        }
+        Ok(broken)
+    }
+
+    /// Write the current state if nothing newer was written yet.
+    async fn save(&self) {
+        let mut saved = self.inner.saved.lock().await;
+        let (version, json) = {
+            let state = self.inner.state.lock().unwrap();
+            if state.version <= *saved {
+                return;
+            }
+            let records: Vec<LockRecord> = state.locks.iter().map(LockRecord::from_lock).collect();
+            (state.version, serde_json::to_vec(&records).expect("lock records serialize"))
+        };
+        let tmp = self.inner.path.with_extension("json.tmp");
+        let res = async {
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            file.write_all(&json).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp, &self.inner.path).await
+        }.await;
+        match res {
+            Ok(()) => *saved = version,
+            Err(e) => log::error!("failed to save dav locks to {}: {}", self.inner.path.display(), e),
+        }
+    }
+}
+
+impl DavLockSystem for FileLs {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> LsFuture<'_, Result<DavLock, DavLock>> {
+        let res = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.prune();
+            let conflict = state.conflict_to_path(path, None, true, &[], shared)
+                .or_else(|| deep.then(|| state.conflict_from_path(path, None, true, &[], shared)).flatten())
+                .cloned();
+            match conflict {
+                Some(conflict) => Err(conflict),
+                None => {
+                    let lock = DavLock {
+                        token: uuid::Uuid::new_v4().urn().to_string(),
+                        path: path.clone(),
+                        principal: principal.map(|s| s.to_string()),
+                        owner: owner.cloned(),
+                        timeout_at: timeout.map(|d| SystemTime::now() + d),
+                        timeout,
+                        shared,
+                        deep,
+                    };
+                    log::debug!("lock {} created on {}", lock.token, path);
+                    state.locks.push(lock.clone());
+                    state.version += 1;
+                    Ok(lock)
+                }
+            }
+        };
+        async move {
+            self.save().await;
+            res
+        }.boxed()
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> LsFuture<'_, Result<(), ()>> {
+        let res = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.prune();
+            match state.lookup(path, token) {
+                Some(idx) => {
+                    state.locks.remove(idx);
+                    state.version += 1;
+                    Ok(())
+                }
+                None => {
+                    log::debug!("unlock: {} not found at {}", token, path);
+                    Err(())
+                }
+            }
+        };
+        async move {
+            self.save().await;
+            res
+        }.boxed()
+    }
+
+    fn refresh(
+        &self,
+        path: &DavPath,
+        token: &str,
+        timeout: Option<Duration>,
+    ) -> LsFuture<'_, Result<DavLock, ()>> {
+        let res = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.prune();
+            match state.lookup(path, token) {
+                Some(idx) => {
+                    let lock = &mut state.locks[idx];
+                    lock.timeout = timeout;
+                    lock.timeout_at = timeout.map(|d| SystemTime::now() + d);
+                    let lock = lock.clone();
+                    state.version += 1;
+                    Ok(lock)
+                }
+                None => Err(()),
+            }
+        };
+        async move {
+            self.save().await;
+            res
+        }.boxed()
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> LsFuture<'_, Result<(), DavLock>> {
+        let res = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.prune();
+            let conflict = state.conflict_to_path(path, principal, ignore_principal, &submitted_tokens, false)
+                .or_else(|| deep.then(|| {
+                    state.conflict_from_path(path, principal, ignore_principal, &submitted_tokens, false)
+                }).flatten());
+            match conflict {
+                Some(conflict) => Err(conflict.clone()),
+                None => Ok(()),
+            }
+        };
+        async move {
+            self.save().await;
+            res
+        }.boxed()
+    }
+
+    fn discover(&self, path: &DavPath) -> LsFuture<'_, Vec<DavLock>> {
+        let locks = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.prune();
+            state.locks.iter()
+                .filter(|l| covers(&l.path, path) && (l.deep || l.path == *path))
+                .cloned()
+                .collect()
+        };
+        async move {
+            self.save().await;
+            locks
+        }.boxed()
+    }
+
+    fn delete(&self, path: &DavPath) -> LsFuture<'_, Result<(), ()>> {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            let before = state.locks.len();
+            state.locks.retain(|l| !covers(path, &l.path));
+            if state.locks.len() != before {
+                state.version += 1;
+            }
+        }
+        async move {
+            self.save().await;
+            Ok(())
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> DavPath {
+        DavPath::new(path).unwrap()
+    }
+
+    const HOUR: Option<Duration> = Some(Duration::from_secs(3600));
+
+    #[tokio::test]
+    async fn exclusive_locks_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let ls = FileLs::open(dir.path().join("locks.json")).unwrap();
+        let deep = ls.lock(&path("/a"), Some("alice"), None, HOUR, false, true).await.unwrap();
+
+        let conflict = ls.lock(&path("/a/b"), Some("bob"), None, HOUR, false, false).await.unwrap_err();
+        assert_eq!(conflict.token, deep.token);
+        ls.lock(&path("/ab"), Some("bob"), None, HOUR, false, false).await.expect("a sibling isn't covered");
+        // a deep lock on the parent conflicts with the child's
+        let child = ls.lock(&path("/c/d"), Some("bob"), None, HOUR, false, false).await.unwrap();
+        assert_eq!(ls.lock(&path("/c"), Some("alice"), None, HOUR, false, true).await.unwrap_err().token, child.token);
+        ls.lock(&path("/c"), Some("alice"), None, HOUR, false, false).await.expect("a shallow lock leaves the child alone");
+
+        assert!(ls.check(&path("/a/b"), Some("alice"), false, false, vec![]).await.is_err());
+        ls.check(&path("/a/b"), Some("alice"), false, false, vec![&deep.token]).await.unwrap();
+        assert!(ls.check(&path("/a/b"), Some("bob"), false, false, vec![&deep.token]).await.is_err(), "the token is alice's");
+        ls.check(&path("/a/b"), Some("bob"), true, false, vec![&deep.token]).await.unwrap();
+
+        ls.unlock(&path("/a/b"), &deep.token).await.unwrap();
+        ls.lock(&path("/a/b"), Some("bob"), None, HOUR, false, false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shared_locks_coexist() {
+        let dir = tempfile::tempdir().unwrap();
+        let ls = FileLs::open(dir.path().join("locks.json")).unwrap();
+        let first = ls.lock(&path("/f"), Some("alice"), None, HOUR, true, false).await.unwrap();
+        let second = ls.lock(&path("/f"), Some("bob"), None, HOUR, true, false).await.unwrap();
+        assert!(ls.lock(&path("/f"), Some("carol"), None, HOUR, false, false).await.is_err());
+        assert_eq!(ls.discover(&path("/f")).await.len(), 2);
+        // writing takes one of the shared locks
+        assert!(ls.check(&path("/f"), Some("alice"), false, false, vec![]).await.is_err());
+        ls.check(&path("/f"), Some("alice"), false, false, vec![&first.token]).await.unwrap();
+        ls.check(&path("/f"), Some("bob"), false, false, vec![&second.token]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn locks_expire_and_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("locks.json");
+        let ls = FileLs::open(&file).unwrap();
+        let kept = ls.lock(&path("/kept"), Some("alice"), None, HOUR, false, true).await.unwrap();
+        let short = ls.lock(&path("/short"), Some("alice"), None, Some(Duration::from_millis(50)), false, false).await.unwrap();
+        assert!(ls.lock(&path("/short"), Some("bob"), None, HOUR, false, false).await.is_err());
+
+        let reopened = FileLs::open(&file).unwrap();
+        let tokens: Vec<String> = reopened.list().await.into_iter().map(|l| l.token).collect();
+        assert_eq!(tokens, [kept.token.clone(), short.token.clone()]);
+        assert!(reopened.check(&path("/kept/x"), Some("bob"), false, false, vec![]).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(reopened.refresh(&path("/short"), &short.token, HOUR).await.is_err(), "an expired lock can't be refreshed");
+        reopened.lock(&path("/short"), Some("bob"), None, HOUR, false, false).await.unwrap();
+        let reopened = FileLs::open(&file).unwrap();
+        let held: Vec<Option<String>> = reopened.discover(&path("/short")).await.into_iter().map(|l| l.principal).collect();
+        assert_eq!(held, [Some("bob".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn break_path_covers_descendants_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let ls = FileLs::open(dir.path().join("locks.json")).unwrap();
+        ls.lock(&path("/m/a"), None, None, HOUR, false, false).await.unwrap();
+        ls.lock(&path("/m/a/b"), None, None, HOUR, false, false).await.unwrap();
+        ls.lock(&path("/m/ab"), None, None, HOUR, false, false).await.unwrap();
+        assert_eq!(ls.break_path("/m/a").await.unwrap(), 2);
+        let left: Vec<String> = ls.list().await.into_iter().map(|l| l.path).collect();
+        assert_eq!(left, ["/m/ab"]);
+    }
+}
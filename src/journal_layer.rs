@@ -0,0 +1,673 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use opendal::raw::{oio, Access, Layer, LayeredAccess, OpCopy, OpDelete, OpList, OpRead, OpRename, OpStat, OpWrite, RpCopy, RpDelete, RpList, RpRead, RpRename, RpStat, RpWrite};
+use opendal::{Buffer, EntryMode, Error, ErrorKind, Metadata, Operator, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Notify;
+
+const READ_CHUNK: usize = 4 * 1024 * 1024;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Write-back journal: writes are acknowledged once they are on local disk
+/// and uploaded to the backend by a background worker.
+///
+/// Until a journaled file is uploaded, read, stat and list answer from the
+/// journal. Entries are persisted as `<id>.data` + `<id>.json` in the journal
+/// dir and reloaded by [`JournalLayer::open`] after a restart.
+#[derive(Clone)]
+pub struct JournalLayer {
+    journal: Arc<Journal>,
+}
+
+impl JournalLayer {
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut entries = BTreeMap::new();
+        let mut data_ids = HashSet::new();
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else { continue };
+            match path.extension().and_then(|s| s.to_str()) {
+                Some("json") => {
+                    let entry: JournalEntry = serde_json::from_slice(&std::fs::read(&path)?)?;
+                    entries.insert(id, entry);
+                }
+                Some("data") => { data_ids.insert(id); }
+                _ => {}
+            }
+        }
+        // drop writes that never completed and entries whose data went missing
+        for id in data_ids.iter().filter(|id| !entries.contains_key(*id)) {
+            log::info!("dropping incomplete journal write {}", id);
+            let _ = std::fs::remove_file(dir.join(format!("{}.data", id)));
+        }
+        let mut by_path: BTreeMap<String, JournalEntry> = BTreeMap::new();
+        for (id, entry) in entries {
+            if !data_ids.contains(&id) {
+                log::warn!("journal entry {} for {} has no data, dropping", id, entry.path);
+                let _ = std::fs::remove_file(dir.join(format!("{}.json", id)));
+                continue;
+            }
+            match by_path.get(&entry.path) {
+                Some(newer) if newer.seq > entry.seq => remove_entry_files(&dir, &entry.id),
+                _ => {
+                    if let Some(older) = by_path.insert(entry.path.clone(), entry) {
+                        remove_entry_files(&dir, &older.id);
+                    }
+                }
+            }
+        }
+        log::info!("journal loaded with {} pending uploads", by_path.len());
+        Ok(JournalLayer {
+            journal: Arc::new(Journal {
+                dir,
+                state: Mutex::new(JournalState {
+                    entries: by_path,
+                    ..Default::default()
+                }),
+                notify: Notify::new(),
+                backend: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// Set the operator pending entries are uploaded through.
+    pub fn attach(&self, backend: Operator) {
+        *self.journal.backend.lock().unwrap() = Some(backend);
+        self.journal.notify.notify_one();
+    }
+
+    pub fn spawn_worker(&self, signal: impl Future<Output=()> + 'static + Send + Clone) {
+        let journal = self.journal.clone();
+        tokio::spawn(async move {
+            journal.worker(signal).await;
+        });
+    }
+}
+
+impl<A: Access> Layer<A> for JournalLayer {
+    type LayeredAccess = JournalAccess<A>;
+
+    fn layer(&self, access: A) -> Self::LayeredAccess {
+        JournalAccess { access, journal: self.journal.clone() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalEntry {
+    id: String,
+    path: String,
+    size: u64,
+    last_modified: DateTime<Utc>,
+    seq: i64,
+}
+
+impl JournalEntry {
+    fn metadata(&self) -> Metadata {
+        Metadata::new(EntryMode::FILE)
+            .with_content_length(self.size)
+            .with_last_modified(self.last_modified)
+    }
+}
+
+#[derive(Default)]
+struct JournalState {
+    /// latest pending entry by path
+    entries: BTreeMap<String, JournalEntry>,
+    /// id of the entry the worker is uploading
+    uploading: Option<String>,
+    /// paths deleted while their entry was uploading
+    tombstones: HashSet<String>,
+    retries: HashMap<String, (u32, Instant)>,
+}
+
+struct Journal {
+    dir: PathBuf,
+    state: Mutex<JournalState>,
+    notify: Notify,
+    backend: Mutex<Option<Operator>>,
+}
+
+fn remove_entry_files(dir: &std::path::Path, id: &str) {
+    let _ = std::fs::remove_file(dir.join(format!("{}.json", id)));
+    let _ = std::fs::remove_file(dir.join(format!("{}.data", id)));
+}
+
+impl Journal {
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.data", id))
+    }
+
+    fn get(&self, path: &str) -> Option<JournalEntry> {
+        self.state.lock().unwrap().entries.get(path).cloned()
+    }
+
+    async fn create_data_file(&self) -> Result<(String, File)> {
+        let dir = self.dir.clone();
+        let (file, path) = tokio::task::spawn_blocking(move || {
+            tempfile::Builder::new().prefix("journal-").suffix(".data").tempfile_in(dir)?.keep().map_err(|e| e.error)
+        })
+            .await
+            .map_err(|e| Error::new(ErrorKind::Unexpected, "journal task failed").set_source(e))?
+            .map_err(io_error)?;
+        let id = path.file_stem().and_then(|s| s.to_str()).expect("journal file name is utf-8").to_string();
+        Ok((id, File::from_std(file)))
+    }
+
+    /// Persist the entry and make it the pending version of its path.
+    async fn commit(&self, path: &str, id: String, size: u64) -> Result<JournalEntry> {
+        let now = Utc::now();
+        let entry = JournalEntry {
+            id,
+            path: path.to_string(),
+            size,
+            last_modified: now,
+            seq: now.timestamp_nanos_opt().unwrap_or_default(),
+        };
+        let json = serde_json::to_vec(&entry).map_err(|e| Error::new(ErrorKind::Unexpected, "failed to serialize journal entry").set_source(e))?;
+        let tmp = self.dir.join(format!("{}.json.tmp", entry.id));
+        let mut file = File::create(&tmp).await.map_err(io_error)?;
+        file.write_all(&json).await.map_err(io_error)?;
+        file.sync_all().await.map_err(io_error)?;
+        tokio::fs::rename(&tmp, self.dir.join(format!("{}.json", entry.id))).await.map_err(io_error)?;
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(old) = state.entries.insert(path.to_string(), entry.clone()) {
+                if state.uploading.as_ref() != Some(&old.id) {
+                    remove_entry_files(&self.dir, &old.id);
+                }
+            }
+        }
+        log::debug!("journaled {} as {} ({} bytes)", path, entry.id, size);
+        self.notify.notify_one();
+        Ok(entry)
+    }
+
+    /// Forget the pending entry of `path`, returns whether there was one.
+    fn remove(&self, path: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(entry) = state.entries.remove(path) else { return false };
+        if state.uploading.as_ref() == Some(&entry.id) {
+            // the worker deletes it remotely once the upload settles
+            state.tombstones.insert(path.to_string());
+        } else {
+            remove_entry_files(&self.dir, &entry.id);
+        }
+        state.retries.remove(&entry.id);
+        true
+    }
+
+    /// Journal a copy of the pending entry at `from` as `to`.
+    async fn copy(&self, from: &JournalEntry, to: &str) -> Result<()> {
+        let (id, file) = self.create_data_file().await?;
+        drop(file);
+        if let Err(e) = tokio::fs::copy(self.data_path(&from.id), self.data_path(&id)).await {
+            let _ = tokio::fs::remove_file(self.data_path(&id)).await;
+            return Err(io_error(e));
+        }
+        self.commit(to, id, from.size).await?;
+        Ok(())
+    }
+
+    /// Oldest entry that isn't waiting for a retry, or when to look again.
+    fn next_pending(&self) -> Result<JournalEntry, Option<Instant>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mut wake = None;
+        let mut next: Option<&JournalEntry> = None;
+        for entry in state.entries.values() {
+            match state.retries.get(&entry.id) {
+                Some((_, at)) if *at > now => {
+                    wake = Some(wake.map_or(*at, |w: Instant| w.min(*at)));
+                }
+                _ if next.is_none_or(|n| n.seq > entry.seq) => next = Some(entry),
+                _ => {}
+            }
+        }
+        let next = next.cloned().ok_or(wake)?;
+        state.uploading = Some(next.id.clone());
+        Ok(next)
+    }
+
+    async fn worker(&self, signal: impl Future<Output=()> + 'static + Send + Clone) {
+        loop {
+            let backend = self.backend.lock().unwrap().clone();
+            let next = match backend {
+                Some(_) => self.next_pending(),
+                None => Err(None),
+            };
+            let entry = match next {
+                Ok(entry) => entry,
+                Err(wake) => {
+                    let sleep = wake.map(|at| at.saturating_duration_since(Instant::now())).unwrap_or(MAX_RETRY_DELAY);
+                    tokio::select! {
+                        _ = self.notify.notified() => {},
+                        _ = tokio::time::sleep(sleep) => {},
+                        _ = signal.clone() => {
+                            log::info!("shutdown signal received, exiting journal worker");
+                            return
+                        },
+                    }
+                    continue;
+                }
+            };
+            let backend = backend.expect("worker only picks entries with a backend");
+            let res = self.upload(&backend, &entry).await;
+            let deleted = self.settle(&entry, &res);
+            if deleted && res.is_ok() {
+                if let Err(e) = backend.delete(&entry.path).await {
+                    log::error!("failed to delete {} after upload: {}", entry.path, e);
+                }
+            }
+        }
+    }
+
+    /// Take the outcome of uploading `entry` and drop its files unless it's
+    /// to be retried. Returns whether the path was deleted meanwhile, the
+    /// upload is deleted remotely then.
+    fn settle(&self, entry: &JournalEntry, res: &Result<()>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.uploading = None;
+        let superseded = state.entries.get(&entry.path).is_none_or(|e| e.id != entry.id);
+        match res {
+            Ok(_) => {
+                log::info!("uploaded journaled {} ({} bytes)", entry.path, entry.size);
+                if !superseded {
+                    state.entries.remove(&entry.path);
+                }
+                state.retries.remove(&entry.id);
+                remove_entry_files(&self.dir, &entry.id);
+            }
+            Err(e) if superseded => {
+                log::warn!("upload of superseded journal entry {} failed: {}", entry.id, e);
+                remove_entry_files(&self.dir, &entry.id);
+            }
+            Err(e) => {
+                let attempts = state.retries.get(&entry.id).map_or(1, |(n, _)| n + 1);
+                let delay = Duration::from_secs(1 << attempts.min(16)).min(MAX_RETRY_DELAY);
+                log::warn!("upload of journaled {} failed (attempt {}), retrying in {:?}: {}", entry.path, attempts, delay, e);
+                state.retries.insert(entry.id.clone(), (attempts, Instant::now() + delay));
+            }
+        }
+        state.tombstones.remove(&entry.path)
+    }
+
+    async fn upload(&self, backend: &Operator, entry: &JournalEntry) -> Result<()> {
+        let mut file = File::open(self.data_path(&entry.id)).await.map_err(io_error)?;
+        let mut writer = backend.writer(&entry.path).await?;
+        loop {
+            let mut chunk = BytesMut::with_capacity(READ_CHUNK);
+            while chunk.len() < READ_CHUNK {
+                if file.read_buf(&mut chunk).await.map_err(io_error)? == 0 {
+                    break;
+                }
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            writer.write(chunk.freeze()).await?;
+        }
+        writer.close().await?;
+        Ok(())
+    }
+}
+
+pub struct JournalAccess<A> where A: Access {
+    access: A,
+    journal: Arc<Journal>,
+}
+
+impl<A: Access> std::fmt::Debug for JournalAccess<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JournalAccess").field("access", &self.access).finish()
+    }
+}
+
+impl<A: Access> LayeredAccess for JournalAccess<A> {
+    type Inner = A;
+    type Reader = oio::Reader;
+    type Writer = JournalWriter;
+    type Lister = JournalLister<A::Lister>;
+    type Deleter = JournalDeleter<A::Deleter>;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.access
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+        let Some(entry) = self.journal.get(path) else {
+            let (rp, reader) = self.access.read(path, args).await?;
+            return Ok((rp, Box::new(reader)));
+        };
+        let mut file = File::open(self.journal.data_path(&entry.id)).await.map_err(io_error)?;
+        let range = args.range();
+        file.seek(std::io::SeekFrom::Start(range.offset())).await.map_err(io_error)?;
+        let remaining = range.size().unwrap_or(u64::MAX).min(entry.size.saturating_sub(range.offset()));
+        Ok((RpRead::new(), Box::new(JournalReader { file, remaining })))
+    }
+
+    async fn write(&self, path: &str, _args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+        let (id, file) = self.journal.create_data_file().await?;
+        Ok((RpWrite::new(), JournalWriter {
+            journal: self.journal.clone(),
+            path: path.to_string(),
+            id,
+            file: Some(file),
+            size: 0,
+        }))
+    }
+
+    async fn stat(&self, path: &str, args: OpStat) -> Result<RpStat> {
+        match self.journal.get(path) {
+            Some(entry) => Ok(RpStat::new(entry.metadata())),
+            None => self.access.stat(path, args).await,
+        }
+    }
+
+    async fn copy(&self, from: &str, to: &str, args: OpCopy) -> Result<RpCopy> {
+        match self.journal.get(from) {
+            Some(entry) => {
+                self.journal.copy(&entry, to).await?;
+                Ok(RpCopy::new())
+            }
+            None => {
+                self.journal.remove(to);
+                self.access.copy(from, to, args).await
+            }
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str, args: OpRename) -> Result<RpRename> {
+        match self.journal.get(from) {
+            Some(entry) => {
+                self.journal.copy(&entry, to).await?;
+                self.journal.remove(from);
+                // an older uploaded version may still live at `from`
+                match self.access.delete().await {
+                    Ok((_, mut deleter)) => {
+                        if let Err(e) = async { oio::Delete::delete(&mut deleter, from, OpDelete::new())?; oio::Delete::flush(&mut deleter).await }.await {
+                            log::warn!("failed to delete {} after journaled rename: {}", from, e);
+                        }
+                    }
+                    Err(e) => log::warn!("failed to delete {} after journaled rename: {}", from, e),
+                }
+                Ok(RpRename::new())
+            }
+            None => {
+                self.journal.remove(to);
+                self.access.rename(from, to, args).await
+            }
+        }
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Lister)> {
+        let recursive = args.recursive();
+        let (rp, lister) = self.access.list(path, args).await?;
+        // the root is listed as "/" while entry paths are relative
+        let prefix = path.trim_start_matches('/');
+        let pending = self.journal.state.lock().unwrap().entries.values()
+            .filter(|e| e.path.starts_with(prefix) && e.path.len() > prefix.len())
+            .filter(|e| recursive || !e.path[prefix.len()..].contains('/'))
+            .map(|e| (e.path.clone(), e.metadata()))
+            .collect();
+        Ok((rp, JournalLister { inner: lister, pending }))
+    }
+
+    async fn delete(&self) -> Result<(RpDelete, Self::Deleter)> {
+        let (rp, deleter) = self.access.delete().await?;
+        Ok((rp, JournalDeleter { inner: deleter, journal: self.journal.clone() }))
+    }
+}
+
+pub struct JournalWriter {
+    journal: Arc<Journal>,
+    path: String,
+    id: String,
+    file: Option<File>,
+    size: u64,
+}
+
+impl oio::Write for JournalWriter {
+    async fn write(&mut self, bs: Buffer) -> Result<()> {
+        let file = self.file.as_mut().ok_or_else(|| Error::new(ErrorKind::Unexpected, "journal writer already closed"))?;
+        self.size += bs.len() as u64;
+        for chunk in bs {
+            file.write_all(&chunk).await.map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<Metadata> {
+        let file = self.file.take().ok_or_else(|| Error::new(ErrorKind::Unexpected, "journal writer already closed"))?;
+        file.sync_all().await.map_err(io_error)?;
+        drop(file);
+        let entry = self.journal.commit(&self.path, self.id.clone(), self.size).await?;
+        Ok(entry.metadata())
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        if self.file.take().is_some() {
+            let _ = tokio::fs::remove_file(self.journal.data_path(&self.id)).await;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for JournalWriter {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(self.journal.data_path(&self.id));
+        }
+    }
+}
+
+pub struct JournalReader {
+    file: File,
+    remaining: u64,
+}
+
+impl oio::Read for JournalReader {
+    async fn read(&mut self) -> Result<Buffer> {
+        let len = (READ_CHUNK as u64).min(self.remaining) as usize;
+        let mut chunk = BytesMut::with_capacity(len);
+        while chunk.len() < len {
+            if self.file.read_buf(&mut chunk).await.map_err(io_error)? == 0 {
+                break;
+            }
+        }
+        self.remaining -= chunk.len() as u64;
+        Ok(chunk.freeze().into())
+    }
+}
+
+/// Lists the backend, answering journaled paths with their pending metadata
+/// and appending journaled files the backend doesn't have yet.
+pub struct JournalLister<L> {
+    inner: L,
+    pending: BTreeMap<String, Metadata>,
+}
+
+impl<L: oio::List> oio::List for JournalLister<L> {
+    async fn next(&mut self) -> Result<Option<oio::Entry>> {
+        if let Some(entry) = self.inner.next().await? {
+            return match self.pending.remove(entry.path()) {
+                Some(meta) => Ok(Some(oio::Entry::new(entry.path(), meta))),
+                None => Ok(Some(entry)),
+            };
+        }
+        Ok(self.pending.pop_first().map(|(path, meta)| oio::Entry::new(&path, meta)))
+    }
+}
+
+pub struct JournalDeleter<D> {
+    inner: D,
+    journal: Arc<Journal>,
+}
+
+impl<D: oio::Delete> oio::Delete for JournalDeleter<D> {
+    fn delete(&mut self, path: &str, args: OpDelete) -> Result<()> {
+        if self.journal.remove(path) {
+            log::debug!("dropped journaled {}", path);
+        }
+        self.inner.delete(path, args)
+    }
+
+    async fn flush(&mut self) -> Result<usize> {
+        self.inner.flush().await
+    }
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Unexpected, "journal io failed").set_source(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use opendal::services::Memory;
+
+    use super::*;
+
+    fn memory() -> Operator {
+        Operator::new(Memory::default()).unwrap().finish()
+    }
+
+    fn journaled(journal_layer: &JournalLayer, backend: &Operator) -> Operator {
+        backend.clone().layer(journal_layer.clone())
+    }
+
+    fn files(dir: &std::path::Path) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn open_replays_pending_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = memory();
+        backend.write("a.txt", "old").await.unwrap();
+        {
+            let journal_layer = JournalLayer::open(dir.path()).unwrap();
+            journaled(&journal_layer, &backend).write("a.txt", "pending").await.unwrap();
+        }
+        // a write that never closed
+        std::fs::write(dir.path().join("journal-stray.data"), "partial").unwrap();
+
+        let journal_layer = JournalLayer::open(dir.path()).unwrap();
+        assert_eq!(files(dir.path()).len(), 2, "the stray data file is dropped");
+        let op = journaled(&journal_layer, &backend);
+        assert_eq!(op.stat("a.txt").await.unwrap().content_length(), 7);
+        assert_eq!(op.read("a.txt").await.unwrap().to_vec(), b"pending");
+        assert_eq!(backend.read("a.txt").await.unwrap().to_vec(), b"old");
+
+        journal_layer.attach(backend.clone());
+        journal_layer.spawn_worker(std::future::pending());
+        for _ in 0..100 {
+            if backend.read("a.txt").await.unwrap().to_vec() == b"pending" && files(dir.path()).is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("the replayed write was not uploaded");
+    }
+
+    #[tokio::test]
+    async fn superseded_upload_keeps_the_newer_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_layer = JournalLayer::open(dir.path()).unwrap();
+        let journal = &journal_layer.journal;
+        let op = journaled(&journal_layer, &memory());
+        op.write("f", "one").await.unwrap();
+        let uploading = journal.next_pending().unwrap();
+
+        op.write("f", "two").await.unwrap();
+        assert!(journal.data_path(&uploading.id).exists(), "the uploading entry's data is kept");
+        assert!(!journal.settle(&uploading, &Ok(())));
+        assert!(!journal.data_path(&uploading.id).exists());
+        let pending = journal.get("f").expect("the newer entry is still pending");
+        assert_ne!(pending.id, uploading.id);
+        assert_eq!(op.read("f").await.unwrap().to_vec(), b"two");
+
+        // a failed upload of a superseded entry isn't retried
+        op.write("f", "three").await.unwrap();
+        assert!(!journal.settle(&pending, &Err(Error::new(ErrorKind::Unexpected, "down"))));
+        assert!(journal.state.lock().unwrap().retries.is_empty());
+        assert_eq!(files(dir.path()).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn delete_during_upload_leaves_a_tombstone() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_layer = JournalLayer::open(dir.path()).unwrap();
+        let journal = &journal_layer.journal;
+        let op = journaled(&journal_layer, &memory());
+        op.write("f", "data").await.unwrap();
+        let uploading = journal.next_pending().unwrap();
+
+        op.delete("f").await.unwrap();
+        assert!(journal.get("f").is_none());
+        assert!(journal.data_path(&uploading.id).exists(), "the upload still reads its data");
+        assert!(journal.settle(&uploading, &Ok(())), "the uploaded file is to be deleted");
+        assert!(files(dir.path()).is_empty());
+        assert!(journal.state.lock().unwrap().tombstones.is_empty());
+
+        // a failed upload isn't retried, the worker skips the remote delete
+        op.write("g", "data").await.unwrap();
+        let uploading = journal.next_pending().unwrap();
+        op.delete("g").await.unwrap();
+        journal.settle(&uploading, &Err(Error::new(ErrorKind::Unexpected, "down")));
+        assert!(files(dir.path()).is_empty());
+        assert!(journal.state.lock().unwrap().retries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_upload_is_retried_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_layer = JournalLayer::open(dir.path()).unwrap();
+        let journal = &journal_layer.journal;
+        journaled(&journal_layer, &memory()).write("f", "data").await.unwrap();
+        let uploading = journal.next_pending().unwrap();
+
+        assert!(!journal.settle(&uploading, &Err(Error::new(ErrorKind::Unexpected, "down"))));
+        assert!(journal.get("f").is_some());
+        assert!(matches!(journal.next_pending(), Err(Some(_))), "waits for the retry");
+    }
+
+    #[tokio::test]
+    async fn list_merges_pending_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_layer = JournalLayer::open(dir.path()).unwrap();
+        let backend = memory();
+        backend.write("a", "old").await.unwrap();
+        backend.write("b", "backend").await.unwrap();
+        let op = journaled(&journal_layer, &backend);
+        op.write("a", "pending a").await.unwrap();
+        op.write("c", "pending c").await.unwrap();
+        op.write("sub/d", "nested").await.unwrap();
+
+        let listed: BTreeMap<String, Metadata> = op.list("/").await.unwrap().into_iter()
+            .filter(|e| e.metadata().is_file())
+            .map(|e| (e.path().to_string(), e.metadata().clone()))
+            .collect();
+        assert_eq!(listed.keys().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(listed["a"].content_length(), 9, "the pending size wins");
+        assert_eq!(listed["c"].content_length(), 9);
+
+        let nested: Vec<String> = op.list_with("/").recursive(true).await.unwrap().into_iter()
+            .filter(|e| e.metadata().is_file())
+            .map(|e| e.path().to_string())
+            .collect();
+        assert!(nested.contains(&"sub/d".to_string()));
+    }
+}
@@ -0,0 +1,61 @@
+use axum::{Json, Router, extract::{Path, Query, State}, middleware, routing::{delete, get}};
+use http::StatusCode;
+use serde::Deserialize;
+
+use crate::basic_auth::{require_user, BasicAuth};
+use crate::file_ls::{FileLs, LockInfo};
+use crate::types::Response;
+
+#[derive(Deserialize)]
+struct BreakQuery {
+    /// url path of the locked resource, including the mount prefix
+    path: String,
+}
+
+async fn list(State(ls): State<FileLs>) -> Json<Response<Vec<LockInfo>>> {
+    Json(Response {
+        code: StatusCode::OK.as_u16(),
+        msg: "success".to_string(),
+        body: ls.list().await,
+    })
+}
+
+async fn break_lock(State(ls): State<FileLs>, Path(token): Path<String>) -> (StatusCode, Json<Response<()>>) {
+    if ls.break_lock(&token).await {
+        (StatusCode::OK, Json(Response {
+            code: StatusCode::OK.as_u16(),
+            msg: "success".to_string(),
+            body: (),
+        }))
+    } else {
+        (StatusCode::NOT_FOUND, Json(Response {
+            code: StatusCode::NOT_FOUND.as_u16(),
+            msg: format!("lock {} not found", token),
+            body: (),
+        }))
+    }
+}
+
+async fn break_path(State(ls): State<FileLs>, Query(query): Query<BreakQuery>) -> (StatusCode, Json<Response<usize>>) {
+    match ls.break_path(&query.path).await {
+        Ok(broken) => (StatusCode::OK, Json(Response {
+            code: StatusCode::OK.as_u16(),
+            msg: "success".to_string(),
+            body: broken,
+        })),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(Response {
+            code: StatusCode::BAD_REQUEST.as_u16(),
+            msg: e.to_string(),
+            body: 0,
+        })),
+    }
+}
+
+/// Breaking locks takes one of the users, their locks are at stake.
+pub fn lock_api_router(ls: FileLs, auth: BasicAuth) -> Router {
+    Router::new()
+        .route("/", get(list).delete(break_path))
+        .route("/{token}", delete(break_lock))
+        .route_layer(middleware::from_fn_with_state(auth, require_user))
+        .with_state(ls)
+}
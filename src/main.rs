@@ -1,38 +1,69 @@
-use std::error::Error as StdError;
-use std::future::IntoFuture;
+use std::collections::BTreeMap;
+use std::future::{Future, IntoFuture};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
-use axum::extract::DefaultBodyLimit;
+use anyhow::{Context, Result};
 use axum::response::Html;
 use axum::routing::get;
+use app_password_handler::app_password_api_router;
+use app_passwords::AppPasswords;
+use basic_auth::{BasicAuth, RequireAuth};
 use buf_layer::BufLayer;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{AccountConfig, BackendConfig, Config, LayerConfig, MountConfig, ServerConfig, TokenStoreKind};
+use meta_cache_layer::MetaCacheLayer;
 use dav::DavHandlerWrapper;
-use bytes::{Buf, Bytes};
-use dav_server::memls::MemLs;
 use dav_server::DavHandler;
 use dav_server_opendalfs::OpendalFs;
+use file_ls::FileLs;
 use futures::FutureExt;
-use mux_layer::MuxLayer;
-use odrive::ODriveState;
-use odrive_handler::onedrive_api_router;
-use opendal::layers::LoggingLayer;
-use opendal::services::{Memory, Onedrive};
-use opendal::{Builder, Operator};
+use lock_handler::lock_api_router;
+use mounts::{LayerPool, Mount};
+use mux_layer::{MuxLayer, MuxRules};
+use odrive::{ClientCertificate, DriveTarget, ODriveConfig, ODriveState, Provider};
+use reload::{LogFilter, Reloader};
+use odrive_handler::{accounts_api_router, accounts_health, health, onedrive_api_router};
+use opendal::layers::{HttpClientLayer, LoggingLayer};
+use opendal::raw::HttpClient;
+use opendal::services::{Dropbox, Fs, Gdrive, Memory, Onedrive, S3, Webdav};
+use opendal::{Builder, Operator, Scheme};
 
 // use reqwest::{Certificate, Proxy};
 use tracing_subscriber::prelude::*;
 use tower_http::trace::TraceLayer;
-use types::OneDriveArgs;
+use session_fetch::SessionFetch;
+use token_store::{CredentialStore, EncryptedFileStore, FileStore, MemoryStore, TokenStore};
 use uninit_svc::UninitSvc;
+use upload_layer::UploadSessionLayer;
 
 use crate::odrive::ODriveSession;
 
+mod account;
+mod app_password_handler;
+mod app_passwords;
+mod basic_auth;
+mod cli;
 mod dav;
 mod buf_layer;
+mod cache_layer;
+mod config;
+mod file_ls;
+mod journal_layer;
+mod lock_handler;
+mod meta_cache_layer;
+mod mounts;
 mod mux_layer;
 mod odrive;
 mod odrive_handler;
+mod reload;
+mod session_fetch;
+mod token_store;
 mod uninit_svc;
+mod upload_layer;
 mod types;
 mod utils;
 
@@ -40,51 +71,243 @@ mod utils;
 /// and rust internally has a search depth limit prevents from resolving
 fn is_fn<F: (Fn(&str) -> bool) + 'static + Send + Sync + Unpin + Clone>(f: F) -> F { f }
 
-fn dav_svc<B, D, E>(args: &OneDriveArgs) -> Result<DavHandlerWrapper> where
-    D: Buf + Send + 'static,
-    E: StdError + Send + Sync + 'static,
-    B: http_body::Body<Data=D, Error=E> + Send + 'static,
-{
+const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The backend of a mount, and the session signing its requests if it
+/// needs one.
+fn mount_backend<'a>(mount: &MountConfig, sessions: &'a BTreeMap<String, ODriveSession>) -> Result<(Operator, Option<&'a ODriveSession>)> {
+    let session = match mount.backend.account() {
+        Some(account) => Some(sessions.get(account).with_context(|| format!("unknown account {}", account))?),
+        None => None,
+    };
+    // the placeholder token only makes opendal sign requests,
+    // SessionFetch swaps in the session's current access token
+    let signed = |op: Operator| match session {
+        Some(session) => op.layer(HttpClientLayer::new(HttpClient::with(SessionFetch::new(reqwest::Client::new(), session.clone())))),
+        None => op,
+    };
+    let op = match &mount.backend {
+        BackendConfig::Onedrive { .. } => signed(Operator::new(Onedrive::default().root(&mount.root).access_token("session"))?.finish()),
+        BackendConfig::Gdrive { .. } => signed(Operator::new(Gdrive::default().root(&mount.root).access_token("session"))?.finish()),
+        BackendConfig::Dropbox { .. } => signed(Operator::new(Dropbox::default().root(&mount.root).access_token("session"))?.finish()),
+        BackendConfig::Fs => Operator::new(Fs::default().root(&mount.root))?.finish(),
+        BackendConfig::S3 { bucket, endpoint, region, access_key_id, secret_access_key } => {
+            let mut builder = S3::default().root(&mount.root).bucket(bucket);
+            if let Some(endpoint) = endpoint {
+                builder = builder.endpoint(endpoint);
+            }
+            if let Some(region) = region {
+                builder = builder.region(region);
+            }
+            // otherwise from the usual AWS_* env vars and files
+            if let Some(access_key_id) = access_key_id {
+                builder = builder.access_key_id(access_key_id);
+            }
+            if let Some(secret_access_key) = secret_access_key {
+                builder = builder.secret_access_key(secret_access_key);
+            }
+            Operator::new(builder)?.finish()
+        }
+        BackendConfig::Webdav { endpoint, username, password } => {
+            let mut builder = Webdav::default().root(&mount.root).endpoint(endpoint);
+            if let Some(username) = username {
+                builder = builder.username(username);
+            }
+            if let Some(password) = password {
+                builder = builder.password(password);
+            }
+            Operator::new(builder)?.finish()
+        }
+        BackendConfig::Memory => Operator::new(Memory::default().root(&mount.root))?.finish(),
+        BackendConfig::Service { scheme, options } => {
+            let scheme = Scheme::from_str(scheme).with_context(|| format!("unknown opendal service {}", scheme))?;
+            let mut options = options.clone();
+            options.entry("root".to_string()).or_insert_with(|| mount.root.clone());
+            Operator::via_iter(scheme, options)?
+        }
+    };
+    Ok((op, session))
+}
+
+/// A mount's dav service: its backend under its layers. Mounts backed by
+/// an account only serve while it's signed in, the others right away.
+/// The layers shared with a current mount only switch over on
+/// [`Mount::activate`].
+fn build_mount(
+    mount: &MountConfig,
+    sessions: &BTreeMap<String, ODriveSession>,
+    server: &ServerConfig,
+    locks: &FileLs,
+    pool: &LayerPool,
+    signal: impl Future<Output = ()> + Send + Clone + 'static,
+) -> Result<Mount> {
     // let cert = Certificate::from_pem(include_bytes!("../cert.pem"))?;
-    // 1drive fs
     // let http_client = HttpClient::with(
     //     reqwest::ClientBuilder::new()
     //     // .proxy(Proxy::https("http://localhost:8080")?)
     //     // .add_root_certificate(cert)
     //     .build()?);
-    let mut builder = Onedrive::default()
-        .root(&args.onedrive_root)
-        .client_id(&args.client_id)
-        .refresh_token(args.refresh_token.as_ref().unwrap());
-    if let Some(client_secret) = args.client_secret.as_ref() {
-        builder = builder.client_secret(client_secret);
-    }
-    let mux_layer = MuxLayer::new(|| Memory::default().build().unwrap(), is_fn(|path| {
-        // split into dir and file
-        let mut parts = path.rsplitn(2, '/');
-        let file = parts.next().unwrap_or(path);
-        // let dir = parts.next().unwrap_or("/");
-        let res = file.starts_with("._") || file.ends_with("DS_Store");
-        log::debug!("route {} to {}", path, if res { "memory" } else { "onedrive" });
-        res
-    }));
-    let op = Operator::new(builder)?
-        .layer(BufLayer::default())
-        .layer(mux_layer)
-        .layer(LoggingLayer::default())
-        .finish();
+    let (mut op, session) = mount_backend(mount, sessions)?;
+
+    let mut upload = None;
+    let mut journals = Vec::new();
+    let mut caches = Vec::new();
+    let mut mux = Vec::new();
+    for layer in mount.layers.iter() {
+        op = match layer {
+            LayerConfig::Buffer { mem_threshold, spill_dir } => {
+                let mut buf_layer = BufLayer::default();
+                if let Some(threshold) = mem_threshold {
+                    buf_layer = buf_layer.mem_threshold(*threshold);
+                }
+                if let Some(dir) = spill_dir.as_ref() {
+                    buf_layer = buf_layer.spill_dir(dir);
+                }
+                op.layer(buf_layer)
+            }
+            // resumable upload sessions, staged in the given dir
+            LayerConfig::Upload { dir, chunk_size } => {
+                let session = session.context("upload sessions only work with onedrive")?;
+                let account = mount.backend.account().unwrap_or_default();
+                let upload_layer = pool.upload(dir, account, &mount.root, || {
+                    let upload_layer = UploadSessionLayer::new(session.clone(), reqwest::Client::new(), &mount.root, dir);
+                    match chunk_size {
+                        Some(chunk_size) => upload_layer.chunk_size(*chunk_size),
+                        None => upload_layer,
+                    }
+                });
+                upload = Some(upload_layer.clone());
+                op.layer(upload_layer)
+            }
+            // write-back journal, its worker uploads through the latest mount activated on it
+            LayerConfig::Journal { dir } => {
+                let (journal_layer, opened) = pool.journal(dir)?;
+                if opened {
+                    journal_layer.spawn_worker(signal.clone());
+                }
+                journals.push((journal_layer.clone(), op.clone()));
+                op.layer(journal_layer)
+            }
+            // local read cache
+            LayerConfig::Cache { dir, size } => {
+                let cache_layer = pool.cache(dir, size.unwrap_or(DEFAULT_CACHE_SIZE))?;
+                caches.push(cache_layer.clone());
+                op.layer(cache_layer)
+            }
+            LayerConfig::MetaCache { ttl } => op.layer(MetaCacheLayer::new(Duration::from_secs(*ttl))),
+            // stash macOS metadata files in memory instead of the backend
+            LayerConfig::Mux { files } => {
+                let rules = MuxRules::new(files.clone());
+                mux.push(rules.clone());
+                op.layer(MuxLayer::new(|| Memory::default().build().unwrap(), is_fn(move |path| {
+                    let res = rules.matches(path);
+                    log::debug!("route {} to {}", path, if res { "memory" } else { "backend" });
+                    res
+                })))
+            }
+        };
+    }
+    let op = op.layer(LoggingLayer::default());
     // dav fs
     let webdavfs = OpendalFs::new(op);
     // http handler
     let dav_config = DavHandler::builder()
-        .strip_prefix("/zotero")
+        .strip_prefix(&mount.prefix)
         .filesystem(webdavfs)
-        .locksystem(MemLs::new());
+        .locksystem(Box::new(locks.clone()));
     let handler = dav_config
         .build_handler();
     // let svc = into_service(handler);
-    let svc = DavHandlerWrapper::new(handler);
-    Ok(svc)
+    let handler = DavHandlerWrapper::new(handler)
+        .max_body_size(mount.max_body_size.or(server.max_body_size));
+
+    // the dav handler (and its lock system) lives as long as the mount,
+    // token refreshes only change what SessionFetch signs with
+    let svc = UninitSvc::new();
+    if session.is_none() {
+        svc.init(handler.clone());
+    }
+    Ok(Mount {
+        config: mount.clone(),
+        svc,
+        handler,
+        upload,
+        resumed: Default::default(),
+        journals,
+        caches,
+        mux,
+    })
+}
+
+/// An account's session: its provider and app registration, drive and token store.
+fn account_session(name: &str, account: &AccountConfig, redirect_url: String) -> Result<ODriveSession> {
+    // key material to encrypt the token file with, either inline or in a file
+    let token_key = match (account.token_key.as_ref(), account.token_key_file.as_ref()) {
+        (Some(key), _) => Some(key.clone().into_bytes()),
+        (None, Some(path)) => Some(std::fs::read(path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .trim_ascii_end().to_vec()),
+        (None, None) => None,
+    };
+    let token_file = account.token_file.clone().unwrap_or_else(|| PathBuf::from(format!("app_data.{}.json", name)));
+    // a provisioned credential takes precedence
+    let store: Arc<dyn TokenStore> = match (account.refresh_token_file.as_ref(), account.token_store) {
+        (Some(path), _) => Arc::new(CredentialStore::new(path)),
+        (None, TokenStoreKind::Memory) => Arc::new(MemoryStore::default()),
+        (None, TokenStoreKind::File) => match token_key {
+            Some(key) => Arc::new(EncryptedFileStore::new(token_file, &key)),
+            None => Arc::new(FileStore::new(token_file)),
+        },
+    };
+    log::info!("account {} token store: {}", name, store.describe());
+
+    let mut onedrive_config = ODriveConfig::new(account.client_id.clone(), redirect_url);
+    onedrive_config.client_secret = account.client_secret.clone();
+    onedrive_config.revocation_url = account.revocation_url.clone();
+    onedrive_config.authority = account.authority.clone();
+    if let Some(tenant) = account.tenant.clone() {
+        onedrive_config.tenant = tenant;
+    }
+    if let Some(graph_url) = account.graph_url.clone() {
+        onedrive_config.graph_url = graph_url;
+    }
+    if account.provider != Provider::Microsoft {
+        onedrive_config.use_provider(account.provider);
+    }
+    // only access the app's folder, the mount roots are then relative to it
+    if account.app_folder {
+        onedrive_config.use_app_folder();
+    }
+    // serve another user's or a SharePoint drive instead of the signed in user's
+    onedrive_config.drive = match (account.drive_user.clone(), account.drive_id.clone()) {
+        (Some(user), _) => DriveTarget::User(user),
+        (None, Some(id)) => DriveTarget::Drive(id),
+        (None, None) => DriveTarget::Me,
+    };
+    // app-only auth for work tenants, with the client secret or a certificate
+    if let Some(path) = account.client_cert_key_file.as_ref() {
+        let key = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let thumbprint = account.client_cert_thumbprint.as_ref().context("client_cert_thumbprint not provided")?;
+        onedrive_config.client_certificate = Some(ClientCertificate::new(&key, thumbprint)?);
+    }
+    if account.app_only {
+        onedrive_config.use_app_only();
+    }
+    if let Some(scopes) = account.scopes.clone() {
+        onedrive_config.scopes = scopes;
+    }
+    let session = ODriveSession::new(
+        reqwest::ClientBuilder::new()
+            .build()
+            .unwrap(),
+        onedrive_config,
+        store,
+    )?;
+    // refresh the access token this many seconds before it expires
+    Ok(match account.refresh_margin {
+        Some(margin) => session.refresh_margin(Duration::from_secs(margin)),
+        None => session,
+    })
 }
 
 // shutdown helper: listen for Ctrl+C and SIGTERM on unix
@@ -113,84 +336,156 @@ async fn shutdown_signal() {
     log::info!("shutdown signal received");
 }
 
+// reload helper: re-read the config on SIGHUP until shutdown
+#[cfg(unix)]
+async fn reload_on_sighup(mut reloader: Reloader, signal: impl Future<Output = ()> + Send + Clone + 'static) {
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to listen for SIGHUP");
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {},
+            _ = signal.clone() => return,
+        }
+        log::info!("SIGHUP received, reloading config");
+        match reloader.reload(signal.clone()).await {
+            Ok(()) => log::info!("config reloaded"),
+            Err(e) => log::error!("config reload failed, keeping the current one: {:#}", e),
+        }
+    }
+}
+
 static GIT_REVISION: &str = env!("GIT_REVISION");
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
+    // the commands print their results to stdout, keep it for them
     #[cfg(not(feature = "console-subscriber"))]
-    tracing_subscriber::fmt::init();
+    let log_filter: Option<LogFilter> = {
+        let writer = match matches!(cli.command, None | Some(Command::Serve)) {
+            true => tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stdout),
+            false => tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stderr),
+        };
+        // RUST_LOG, or the config's log_level once it's loaded
+        let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+        let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_subscriber::fmt::layer().with_writer(writer))
+            .init();
+        Some(handle)
+    };
 
     log::info!("paperfs version: {}", GIT_REVISION);
     log::debug!("debug logging enabled");
     
     #[cfg(feature = "console-subscriber")]
-    {
+    let log_filter: Option<LogFilter> = {
         let console_layer = console_subscriber::spawn();
         tracing_subscriber::registry()
             .with(console_layer)
             // .with(tracing_subscriber::filter::EnvFilter::from_default_env())
             .with(tracing_subscriber::fmt::layer())
             .init();
+        None
+    };
+
+    if let Some(Command::HashPassword) = cli.command {
+        if let Err(e) = cli::hash_password() {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-    
-    // get paraemters from env
-    let onedrive_root = std::env::var("ONEDRIVE_ROOT").expect("ONEDRIVE_ROOT not provided");
-    let onedrive_client_id = std::env::var("ONEDRIVE_CLIENT_ID").expect("ONEDRIVE_CLIENT_ID not provided");
-    let onedrive_client_secret = std::env::var("ONEDRIVE_CLIENT_SECRET").ok(); // optional
-    // let onedrive_access_token = std::env::var("ONEDRIVE_ACCESS_TOKEN").unwrap();
-    let bind_addr = std::env::var("PAPERFS_BIND_ADDR").ok().unwrap_or_else(|| "0.0.0.0:3000".to_string());
-    let exposed_url = std::env::var("PAPERFS_EXPOSED_URL").ok().unwrap_or_else(|| "http://localhost:3000".to_string());
 
+    // a config file, or the env vars describing a mount per account
+    let config = match cli.config.as_ref() {
+        Some(path) => Config::load(path),
+        None => Config::from_env(),
+    };
+    let config = config.unwrap_or_else(|e| {
+        eprintln!("invalid config: {:#}", e);
+        std::process::exit(2);
+    });
+    if let (Some(handle), Some(filter)) = (log_filter.as_ref(), reload::log_filter(&config.server)) {
+        handle.reload(filter).expect("failed to set the log level");
+    }
+
+    let res = match cli.command {
+        None | Some(Command::Serve) => {
+            serve(config, cli.config, log_filter).await;
+            Ok(())
+        }
+        Some(Command::Login { account, browser }) => cli::login(&config, account, browser).await,
+        Some(Command::Status { account, server }) => cli::status(&config, account, server).await,
+        Some(Command::CheckConfig) => cli::check_config(&config).await,
+        Some(Command::Refresh { account, server, user }) => cli::refresh(&config, account, server, user).await,
+        Some(Command::HashPassword) => unreachable!(),
+    };
+    if let Err(e) = res {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(config: Config, path: Option<PathBuf>, log_filter: Option<LogFilter>) {
     // shudown signal
     let signal = shutdown_signal().shared();
 
-    // dav service
-    let svc = UninitSvc::new();
+    let mut sessions = BTreeMap::new();
+    for (name, account) in config.accounts.iter() {
+        let session = account_session(name, account, config.redirect_url(name)).expect("failed to construct onedrive session");
+        sessions.insert(name.clone(), session);
+    }
 
-    // onedrive session
-    let session = ODriveSession::new(
-        reqwest::ClientBuilder::new()
-            .build()
-            .unwrap(),
-        onedrive_client_id.clone(),
-        onedrive_client_secret.clone(),
-        format!("{}/api/v1/onedrive/callback", exposed_url),
-    ).expect("failed to construct onedrive session");
-
-    // connects auth to dav svc init
-    let onedrive_args = OneDriveArgs {
-        onedrive_root: onedrive_root,
-        client_id: onedrive_client_id.clone(),
-        client_secret: onedrive_client_secret.clone(),
-        ..Default::default()
-    };
-    let svc_ = svc.clone();
-    session.on_auth(Box::new(move |state: ODriveState| {
-        let svc = svc_.clone();
-        let onedrive_args = onedrive_args.clone();
-        async move {
-            svc.init(dav_svc::<axum::body::Body, Bytes, axum::Error>(&OneDriveArgs {
-                refresh_token: state.refresh_token.clone(),
-                expires_in: state.expires_at,
-                ..onedrive_args.clone()
-            }).expect("failed to create dav svc")).await
-        } 
-    })).await;
-    session.spawn_token_thread(signal.clone());
-
-    // axum router
-    let router = axum::Router::new()
+    // one lock system for all mounts, locks are kept by their full url path
+    let locks = FileLs::open(&config.server.lock_file).expect("failed to load dav locks");
+    let app_passwords = AppPasswords::open(config.auth.app_passwords_file()).expect("failed to load app passwords");
+    let mut reloader = Reloader::new(path, &config, sessions.clone(), locks.clone(), BasicAuth::new(app_passwords), log_filter);
+    if let Err(e) = reloader.apply(config.clone(), signal.clone()).await {
+        panic!("{:#}", e);
+    }
+    let mounts = reloader.mounts();
+
+    // axum router, requests outside of it go to the mounts, behind the dav users
+    let mut router = axum::Router::new()
         .route("/", get(Html(include_str!("../static/index.html"))))
-        // hacky, but mandatory due to axum's limitation
-        .route_service("/zotero", svc.clone())
-        .route_service("/zotero/", svc.clone())
-        .route_service("/zotero/{*ignore}", svc.clone())
-        .nest("/api/v1/onedrive", onedrive_api_router(session.clone()))
-        .layer(TraceLayer::new_for_http())
-        .layer(DefaultBodyLimit::max(64 * 1024 * 1024));
+        .nest("/api/v1/accounts", accounts_api_router(reloader.accounts()))
+        .nest("/api/v1/locks", lock_api_router(locks.clone(), reloader.auth()))
+        .nest("/api/v1/app_passwords", app_password_api_router(reloader.auth()))
+        .fallback_service(RequireAuth::new(mounts.clone(), reloader.auth()));
+    for (name, session) in sessions.iter() {
+        router = router.nest(&config.api_prefix(name), onedrive_api_router(session.clone(), reloader.auth()));
+        // the account's mounts serve while it's signed in, whichever are mounted by then
+        let (mounts_, name_) = (mounts.clone(), name.clone());
+        session.on_auth(Box::new(move |_: ODriveState| {
+            let (mounts, name) = (mounts_.clone(), name_.clone());
+            async move {
+                mounts.signed_in(&name);
+            }
+        })).await;
+        // logging out or revoked tokens take them down until the next sign in
+        let (mounts_, name_, provider) = (mounts.clone(), name.clone(), session.provider());
+        session.on_signed_out(Box::new(move |reason: String| {
+            let (mounts, name) = (mounts_.clone(), name_.clone());
+            async move {
+                mounts.signed_out(&name, &format!("{} {}", provider, reason));
+            }
+        })).await;
+        session.spawn_token_thread(signal.clone());
+    }
+    let router = match sessions.values().collect::<Vec<_>>().as_slice() {
+        [session] => router.route("/api/v1/health", get(health).with_state((*session).clone())),
+        _ => router.route("/api/v1/health", get(accounts_health).with_state(sessions.into_iter().collect())),
+    };
+    let router = router.layer(TraceLayer::new_for_http());
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(reloader, signal.clone()));
 
     // parse bind address and start hyper server with graceful shutdown
-    let addr: std::net::SocketAddr = bind_addr.parse().expect("invalid bind address");
+    let addr: std::net::SocketAddr = config.server.bind_addr.parse().expect("invalid bind address");
     log::info!("Listening on http://{}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await.expect("failed to bind address");
     let server = axum::serve(listener, router).with_graceful_shutdown(signal).into_future();
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use opendal::raw::{oio, Access, Accessor, Layer, LayeredAccess, OpCopy, OpCreateDir, OpDelete, OpList, OpRead, OpRename, OpStat, OpWrite, RpCopy, RpCreateDir, RpDelete, RpList, RpRead, RpRename, RpStat, RpWrite};
+use opendal::{Buffer, Lister, Metadata, Operator, Result};
+
+/// Caches `stat` and `list` results for `ttl`.
+///
+/// A completed non-recursive list also answers `stat` for every entry it
+/// returned. Writes, deletes, create_dir, copies and renames through the
+/// layer drop the affected paths and their parent listings.
+///
+/// Only layers over a type erased [`Accessor`], since list metadata is only
+/// reachable through the public [`Lister`].
+#[derive(Clone)]
+pub struct MetaCacheLayer {
+    cache: Arc<MetaCache>,
+}
+
+impl MetaCacheLayer {
+    pub fn new(ttl: Duration) -> Self {
+        MetaCacheLayer {
+            cache: Arc::new(MetaCache {
+                ttl,
+                state: Mutex::new(MetaState::default()),
+            }),
+        }
+    }
+}
+
+impl Layer<Accessor> for MetaCacheLayer {
+    type LayeredAccess = MetaCacheAccess;
+
+    fn layer(&self, access: Accessor) -> Self::LayeredAccess {
+        MetaCacheAccess { access, cache: self.cache.clone() }
+    }
+}
+
+#[derive(Default)]
+struct MetaState {
+    stats: HashMap<String, (Metadata, Instant)>,
+    lists: HashMap<String, (Vec<(String, Metadata)>, Instant)>,
+    /// bumped on every invalidation, results fetched across a bump are dropped
+    generation: u64,
+}
+
+struct MetaCache {
+    ttl: Duration,
+    state: Mutex<MetaState>,
+}
+
+/// Directory listing that contains `path`, "/" for top level entries.
+fn parent(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rfind('/') {
+        Some(i) => &trimmed[..=i],
+        None => "/",
+    }
+}
+
+impl MetaCache {
+    fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    fn stat(&self, path: &str) -> Option<Metadata> {
+        let state = self.state.lock().unwrap();
+        state.stats.get(path)
+            .filter(|(_, at)| at.elapsed() < self.ttl)
+            .map(|(meta, _)| meta.clone())
+    }
+
+    fn list(&self, path: &str) -> Option<Vec<(String, Metadata)>> {
+        let state = self.state.lock().unwrap();
+        state.lists.get(path)
+            .filter(|(_, at)| at.elapsed() < self.ttl)
+            .map(|(entries, _)| entries.clone())
+    }
+
+    fn put_stat(&self, generation: u64, path: &str, meta: Metadata) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            state.stats.insert(path.to_string(), (meta, Instant::now()));
+        }
+    }
+
+    fn put_list(&self, generation: u64, path: &str, entries: Vec<(String, Metadata)>) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        let now = Instant::now();
+        for (entry_path, meta) in entries.iter() {
+            // a listing carries its own dir, keep a proper stat of it if there is one
+            if entry_path != path || !state.stats.contains_key(entry_path) {
+                state.stats.insert(entry_path.clone(), (meta.clone(), now));
+            }
+        }
+        state.lists.insert(path.to_string(), (entries, now));
+        // drop what expired while we're at it
+        let ttl = self.ttl;
+        state.stats.retain(|_, (_, at)| at.elapsed() < ttl);
+        state.lists.retain(|_, (_, at)| at.elapsed() < ttl);
+    }
+
+    /// Forget `path`, everything below it, and the listing of its parent.
+    fn invalidate(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        let dir = format!("{}/", path.trim_end_matches('/'));
+        state.stats.retain(|p, _| p != path && !p.starts_with(&dir));
+        state.lists.retain(|p, _| p != path && !p.starts_with(&dir));
+        state.lists.remove(parent(path));
+    }
+}
+
+pub struct MetaCacheAccess {
+    access: Accessor,
+    cache: Arc<MetaCache>,
+}
+
+impl std::fmt::Debug for MetaCacheAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetaCacheAccess").field("access", &self.access).finish()
+    }
+}
+
+impl LayeredAccess for MetaCacheAccess {
+    type Inner = Accessor;
+    type Reader = oio::Reader;
+    type Writer = MetaCacheWriter;
+    type Lister = oio::Lister;
+    type Deleter = MetaCacheDeleter;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.access
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+        self.access.read(path, args).await
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+        self.cache.invalidate(path);
+        let (rp, writer) = self.access.write(path, args).await?;
+        Ok((rp, MetaCacheWriter { inner: writer, cache: self.cache.clone(), path: path.to_string() }))
+    }
+
+    async fn stat(&self, path: &str, args: OpStat) -> Result<RpStat> {
+        let plain = args.if_match().is_none()
+            && args.if_none_match().is_none()
+            && args.if_modified_since().is_none()
+            && args.if_unmodified_since().is_none()
+            && args.version().is_none();
+        if !plain {
+            return self.access.stat(path, args).await;
+        }
+        if let Some(meta) = self.cache.stat(path) {
+            log::debug!("meta cache hit {}", path);
+            return Ok(RpStat::new(meta));
+        }
+        let generation = self.cache.generation();
+        let rp = self.access.stat(path, args).await?;
+        let meta = rp.into_metadata();
+        self.cache.put_stat(generation, path, meta.clone());
+        Ok(RpStat::new(meta))
+    }
+
+    async fn create_dir(&self, path: &str, args: OpCreateDir) -> Result<RpCreateDir> {
+        self.cache.invalidate(path);
+        self.access.create_dir(path, args).await
+    }
+
+    async fn copy(&self, from: &str, to: &str, args: OpCopy) -> Result<RpCopy> {
+        self.cache.invalidate(to);
+        let res = self.access.copy(from, to, args).await;
+        self.cache.invalidate(to);
+        res
+    }
+
+    async fn rename(&self, from: &str, to: &str, args: OpRename) -> Result<RpRename> {
+        self.cache.invalidate(from);
+        self.cache.invalidate(to);
+        let res = self.access.rename(from, to, args).await;
+        self.cache.invalidate(from);
+        self.cache.invalidate(to);
+        res
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Lister)> {
+        let plain = !args.recursive() && args.start_after().is_none() && args.limit().is_none()
+            && !args.versions() && !args.deleted();
+        if !plain {
+            let (rp, lister) = self.access.list(path, args).await?;
+            return Ok((rp, Box::new(lister)));
+        }
+        if let Some(entries) = self.cache.list(path) {
+            log::debug!("meta cache hit list {}", path);
+            return Ok((RpList::default(), Box::new(CachedLister { entries: entries.into_iter() })));
+        }
+        let generation = self.cache.generation();
+        let lister = Operator::from_inner(self.access.clone()).lister(path).await?;
+        Ok((RpList::default(), Box::new(MetaCacheLister {
+            inner: lister,
+            cache: self.cache.clone(),
+            path: path.to_string(),
+            generation,
+            entries: Vec::new(),
+        })))
+    }
+
+    async fn delete(&self) -> Result<(RpDelete, Self::Deleter)> {
+        let (rp, deleter) = self.access.delete().await?;
+        Ok((rp, MetaCacheDeleter { inner: deleter, cache: self.cache.clone(), paths: Vec::new() }))
+    }
+}
+
+pub struct MetaCacheLister {
+    inner: Lister,
+    cache: Arc<MetaCache>,
+    path: String,
+    generation: u64,
+    entries: Vec<(String, Metadata)>,
+}
+
+impl oio::List for MetaCacheLister {
+    async fn next(&mut self) -> Result<Option<oio::Entry>> {
+        match self.inner.next().await {
+            Some(entry) => {
+                let (path, meta) = entry?.into_parts();
+                self.entries.push((path.clone(), meta.clone()));
+                Ok(Some(oio::Entry::with(path, meta)))
+            }
+            None => {
+                let entries = std::mem::take(&mut self.entries);
+                if !entries.is_empty() {
+                    self.cache.put_list(self.generation, &self.path, entries);
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+pub struct CachedLister {
+    entries: std::vec::IntoIter<(String, Metadata)>,
+}
+
+impl oio::List for CachedLister {
+    async fn next(&mut self) -> Result<Option<oio::Entry>> {
+        Ok(self.entries.next().map(|(path, meta)| oio::Entry::with(path, meta)))
+    }
+}
+
+pub struct MetaCacheWriter {
+    inner: oio::Writer,
+    cache: Arc<MetaCache>,
+    path: String,
+}
+
+impl oio::Write for MetaCacheWriter {
+    async fn write(&mut self, bs: Buffer) -> Result<()> {
+        self.inner.write(bs).await
+    }
+
+    async fn close(&mut self) -> Result<Metadata> {
+        let res = self.inner.close().await;
+        self.cache.invalidate(&self.path);
+        res
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.inner.abort().await
+    }
+}
+
+pub struct MetaCacheDeleter {
+    inner: oio::Deleter,
+    cache: Arc<MetaCache>,
+    paths: Vec<String>,
+}
+
+impl oio::Delete for MetaCacheDeleter {
+    fn delete(&mut self, path: &str, args: OpDelete) -> Result<()> {
+        self.cache.invalidate(path);
+        self.paths.push(path.to_string());
+        self.inner.delete(path, args)
+    }
+
+    async fn flush(&mut self) -> Result<usize> {
+        let res = self.inner.flush().await;
+        // a stat racing with the flush may have cached the deleted entries
+        for path in self.paths.drain(..) {
+            self.cache.invalidate(&path);
+        }
+        res
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use axum::body::Body;
+use axum::response::IntoResponse;
+use http::{Request, StatusCode};
+use opendal::Operator;
+use tower_service::Service;
+
+use crate::cache_layer::CacheLayer;
+use crate::config::{LayerConfig, MountConfig};
+use crate::dav::DavHandlerWrapper;
+use crate::journal_layer::JournalLayer;
+use crate::mux_layer::MuxRules;
+use crate::uninit_svc::UninitSvc;
+use crate::upload_layer::UploadSessionLayer;
+
+/// A built mount: its dav service, and the handles a reload retunes it
+/// through.
+#[derive(Clone)]
+pub struct Mount {
+    pub config: MountConfig,
+    pub svc: UninitSvc<DavHandlerWrapper>,
+    /// installed in `svc` while the mount's account is signed in
+    pub handler: DavHandlerWrapper,
+    pub upload: Option<UploadSessionLayer>,
+    /// whether the pending uploads were resumed since the account signed in
+    pub resumed: Arc<AtomicBool>,
+    /// with the stack below each, what the journal uploads through
+    pub journals: Vec<(JournalLayer, Operator)>,
+    /// in the order of their layers
+    pub caches: Vec<CacheLayer>,
+    pub mux: Vec<MuxRules>,
+}
+
+impl Mount {
+    /// Serve the mount, resuming its pending uploads unless a token refresh
+    /// is all that happened.
+    pub fn signed_in(&self) {
+        self.svc.init(self.handler.clone());
+        if let Some(upload_layer) = self.upload.as_ref() {
+            if !self.resumed.swap(true, Ordering::Relaxed) {
+                upload_layer.spawn_resume();
+            }
+        }
+    }
+
+    /// Take over the layers shared with the mount this one replaces, once
+    /// it's certain to be swapped in: the journals upload through its
+    /// stack and the caches take its sizes.
+    pub fn activate(&self) {
+        for (journal_layer, op) in self.journals.iter() {
+            journal_layer.attach(op.clone());
+        }
+        self.set_cache_sizes(&self.config);
+    }
+
+    fn set_cache_sizes(&self, config: &MountConfig) {
+        let sizes = config.layers.iter().filter_map(|layer| match layer {
+            LayerConfig::Cache { size, .. } => Some(size.unwrap_or(crate::DEFAULT_CACHE_SIZE)),
+            _ => None,
+        });
+        for (cache_layer, size) in self.caches.iter().zip(sizes) {
+            cache_layer.set_capacity(size);
+        }
+    }
+
+    /// Take the cache sizes and mux files of `config`, see
+    /// [`MountConfig::retunable_to`].
+    pub fn retune(&mut self, config: &MountConfig) {
+        self.set_cache_sizes(config);
+        let files = config.layers.iter().filter_map(|layer| match layer {
+            LayerConfig::Mux { files } => Some(files),
+            _ => None,
+        });
+        for (rules, files) in self.mux.iter().zip(files) {
+            rules.set(files.clone());
+        }
+        self.config = config.clone();
+    }
+}
+
+/// The mounts being served, by prefix.
+///
+/// Requests look up their mount on the way in, so a reload swaps mounts
+/// without rebuilding the router, and in-flight requests finish on the
+/// mount they started with.
+#[derive(Clone, Default)]
+pub struct Mounts {
+    mounts: Arc<ArcSwap<BTreeMap<String, Mount>>>,
+}
+
+impl Mounts {
+    pub fn load(&self) -> Arc<BTreeMap<String, Mount>> {
+        self.mounts.load_full()
+    }
+
+    pub fn store(&self, mounts: BTreeMap<String, Mount>) {
+        self.mounts.store(Arc::new(mounts));
+    }
+
+    /// Serve the mounts of `account`, it signed in or refreshed its token.
+    pub fn signed_in(&self, account: &str) {
+        for mount in self.mounts.load().values().filter(|m| m.config.backend.account() == Some(account)) {
+            mount.signed_in();
+        }
+    }
+
+    /// Take the mounts of `account` down, answering 503 with `reason`.
+    pub fn signed_out(&self, account: &str, reason: &str) {
+        for mount in self.mounts.load().values().filter(|m| m.config.backend.account() == Some(account)) {
+            mount.svc.reset(reason);
+            mount.resumed.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// The mount with the longest prefix `path` is under.
+    fn find(&self, path: &str) -> Option<UninitSvc<DavHandlerWrapper>> {
+        self.mounts.load().values()
+            .filter(|m| path.strip_prefix(m.config.prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .max_by_key(|m| m.config.prefix.len())
+            .map(|m| m.svc.clone())
+    }
+}
+
+impl Service<Request<Body>> for Mounts {
+    type Response = axum::response::Response;
+    type Error = std::convert::Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let Some(mut svc) = self.find(req.uri().path()) else {
+            return Box::pin(async { Ok(StatusCode::NOT_FOUND.into_response()) });
+        };
+        svc.call(req)
+    }
+}
+
+/// The layers keeping state in a dir, shared by the mounts built on it so a
+/// rebuilt mount takes over its predecessor's instead of opening the dir
+/// a second time.
+#[derive(Default)]
+pub struct LayerPool {
+    journals: Mutex<HashMap<PathBuf, JournalLayer>>,
+    caches: Mutex<HashMap<PathBuf, CacheLayer>>,
+    /// by dir, account and root, the pending uploads are for that root
+    uploads: Mutex<HashMap<(PathBuf, String, String), UploadSessionLayer>>,
+}
+
+impl LayerPool {
+    /// The journal in `dir`, and whether it was just opened.
+    pub fn journal(&self, dir: &Path) -> anyhow::Result<(JournalLayer, bool)> {
+        let mut journals = self.journals.lock().unwrap();
+        if let Some(journal_layer) = journals.get(dir) {
+            return Ok((journal_layer.clone(), false));
+        }
+        let journal_layer = JournalLayer::open(dir).context("failed to open journal")?;
+        journals.insert(dir.to_path_buf(), journal_layer.clone());
+        Ok((journal_layer, true))
+    }
+
+    /// The cache in `dir`, opened with `capacity`. One already open keeps its
+    /// own until a mount on it is activated.
+    pub fn cache(&self, dir: &Path, capacity: u64) -> anyhow::Result<CacheLayer> {
+        let mut caches = self.caches.lock().unwrap();
+        if let Some(cache_layer) = caches.get(dir) {
+            return Ok(cache_layer.clone());
+        }
+        let cache_layer = CacheLayer::open(dir, capacity).context("failed to open read cache")?;
+        caches.insert(dir.to_path_buf(), cache_layer.clone());
+        Ok(cache_layer)
+    }
+
+    pub fn upload(&self, dir: &Path, account: &str, root: &str, open: impl FnOnce() -> UploadSessionLayer) -> UploadSessionLayer {
+        self.uploads.lock().unwrap()
+            .entry((dir.to_path_buf(), account.to_string(), root.to_string()))
+            .or_insert_with(open)
+            .clone()
+    }
+}
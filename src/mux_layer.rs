@@ -3,12 +3,54 @@ use std::future::Future;
 use std::ops::DerefMut;
 use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use futures::lock::Mutex;
 use opendal::raw::oio::List;
 use opendal::raw::*;
 use opendal::ErrorKind;
 use opendal::Result;
 
+/// File name patterns routed to the `a` side, `*` matches any run of
+/// characters. Shared by the layer and swapped on reload.
+#[derive(Clone)]
+pub struct MuxRules {
+    patterns: Arc<ArcSwap<Vec<String>>>,
+}
+
+impl MuxRules {
+    pub fn new(patterns: Vec<String>) -> Self {
+        MuxRules { patterns: Arc::new(ArcSwap::from_pointee(patterns)) }
+    }
+
+    pub fn set(&self, patterns: Vec<String>) {
+        self.patterns.store(Arc::new(patterns));
+    }
+
+    /// Whether the file name of `path` matches a pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        let file = path.rsplit('/').next().unwrap_or(path);
+        self.patterns.load().iter().any(|pattern| glob(pattern, file))
+    }
+}
+
+fn glob(pattern: &str, name: &str) -> bool {
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(mut name) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or("");
+    for part in parts {
+        match name.find(part) {
+            Some(i) => name = &name[i + part.len()..],
+            None => return false,
+        }
+    }
+    name.len() >= last.len() && name.ends_with(last)
+}
+
 /// Hopped it can function as a multiplexer of accessors
 /// but turns out it's hard to take care of all possible semantic differences
 /// eg. memory doesn't support create_dir
@@ -1,31 +1,284 @@
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
 use thiserror::{Error as ThisError};
-use tokio::fs::{File, read_to_string};
-use tokio::io::AsyncWriteExt;
 use tokio::time::sleep;
 use std::pin::Pin;
 use std::future::Future;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use anyhow::{Context, Error as AnyError};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
 use oauth2::*;
-use oauth2::basic::{BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse, BasicTokenType};
+use oauth2::basic::{BasicErrorResponse, BasicErrorResponseType, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse, BasicTokenType};
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::sync::{Mutex, Notify};
 use oauth2::url::Url;
 
-use crate::utils::{AsyncHook, LogError, log_and_go};
+use crate::token_store::TokenStore;
+use crate::utils::{AsyncHook, log_and_go};
 
-const APP_DATA_PATH: &str = "app_data.json";
-const AUTH_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
-const TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
-const SCOPES: &[&str] = &[
+const DEFAULT_AUTHORITY: &str = "https://login.microsoftonline.com";
+const DEFAULT_TENANT: &str = "common";
+/// what opendal's onedrive service talks to, see [`SessionFetch`](crate::session_fetch::SessionFetch)
+pub const DEFAULT_GRAPH_URL: &str = "https://graph.microsoft.com/v1.0";
+pub const DEFAULT_DRIVE_ROOT_URL: &str = "https://graph.microsoft.com/v1.0/me/drive/root";
+const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(600);
+/// refresh interval of a token that came without an expiry
+const UNKNOWN_EXPIRY_REFRESH: Duration = Duration::from_secs(900);
+/// how often to look again while nobody is signed in
+const IDLE_POLL: Duration = Duration::from_secs(60);
+const DEFAULT_SCOPES: &[&str] = &[
     "Files.Read",
     "Files.ReadWrite",
     "offline_access", // this scope is required for refresh token
     "openid", // for id_token
 ];
+const APP_FOLDER_SCOPES: &[&str] = &[
+    "Files.ReadWrite.AppFolder",
+    "offline_access",
+    "openid",
+];
+const GOOGLE_SCOPES: &[&str] = &["https://www.googleapis.com/auth/drive"];
+const DROPBOX_SCOPES: &[&str] = &[
+    "files.metadata.read",
+    "files.content.read",
+    "files.content.write",
+];
+/// tenants that aren't one, app-only tokens need a real one
+const MULTI_TENANTS: &[&str] = &["common", "organizations", "consumers"];
+const JWT_BEARER_ASSERTION: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+/// lifetime of a client assertion, it's only used once right away
+const ASSERTION_LIFETIME: u64 = 600;
+
+/// Who issues a session's tokens, and so which storage they're good for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    /// Microsoft identity platform, for OneDrive and SharePoint
+    #[default]
+    Microsoft,
+    Google,
+    Dropbox,
+}
+
+impl Provider {
+    fn default_scopes(self) -> &'static [&'static str] {
+        match self {
+            Provider::Microsoft => DEFAULT_SCOPES,
+            Provider::Google => GOOGLE_SCOPES,
+            Provider::Dropbox => DROPBOX_SCOPES,
+        }
+    }
+
+    /// Authorization parameters asking for a refresh token, microsoft only
+    /// wants the `offline_access` scope.
+    fn offline_params(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Provider::Microsoft => &[],
+            // consent again, or google only hands out a refresh token the first time
+            Provider::Google => &[("access_type", "offline"), ("prompt", "consent")],
+            Provider::Dropbox => &[("token_access_type", "offline")],
+        }
+    }
+}
+
+impl std::fmt::Display for Provider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Provider::Microsoft => "OneDrive",
+            Provider::Google => "Google Drive",
+            Provider::Dropbox => "Dropbox",
+        })
+    }
+}
+
+/// The drive the session serves.
+#[derive(Debug, Clone, Default)]
+pub enum DriveTarget {
+    /// the signed in user's OneDrive
+    #[default]
+    Me,
+    /// a user's OneDrive, by id or user principal name
+    User(String),
+    /// any drive by id, e.g. a SharePoint document library
+    Drive(String),
+}
+
+impl DriveTarget {
+    fn path(&self) -> String {
+        match self {
+            DriveTarget::Me => "me/drive".to_string(),
+            DriveTarget::User(id) => format!("users/{}/drive", id),
+            DriveTarget::Drive(id) => format!("drives/{}", id),
+        }
+    }
+
+    /// The user owning the drive, if it's a user's.
+    fn user_path(&self) -> Option<String> {
+        match self {
+            DriveTarget::Me => Some("me".to_string()),
+            DriveTarget::User(id) => Some(format!("users/{}", id)),
+            DriveTarget::Drive(_) => None,
+        }
+    }
+}
+
+/// Certificate credential of the app registration, signs the client
+/// assertions of app-only sessions.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    key: EncodingKey,
+    /// base64url sha1 thumbprint of the certificate
+    x5t: String,
+}
+
+impl std::fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCertificate").field("x5t", &self.x5t).finish()
+    }
+}
+
+impl ClientCertificate {
+    /// `key_pem` is the certificate's RSA private key, `thumbprint` the hex
+    /// sha1 thumbprint the app registration shows for it.
+    pub fn new(key_pem: &[u8], thumbprint: &str) -> anyhow::Result<Self> {
+        let key = EncodingKey::from_rsa_pem(key_pem).context("invalid certificate private key")?;
+        let hex: String = thumbprint.chars().filter(|c| *c != ':').collect();
+        anyhow::ensure!(hex.len() == 40 && hex.is_ascii(), "invalid certificate thumbprint, expected 40 hex digits");
+        let digest = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .context("invalid certificate thumbprint")?;
+        Ok(ClientCertificate { key, x5t: BASE64_URL.encode(digest) })
+    }
+
+    /// A signed jwt proving the app's identity to the token endpoint `audience`.
+    fn assertion(&self, client_id: &str, audience: &str) -> anyhow::Result<String> {
+        let mut header = Header::new(Algorithm::RS256);
+        header.x5t = Some(self.x5t.clone());
+        let now = now_secs();
+        let claims = serde_json::json!({
+            "aud": audience,
+            "iss": client_id,
+            "sub": client_id,
+            "jti": uuid::Uuid::new_v4().to_string(),
+            "nbf": now,
+            "iat": now,
+            "exp": now + ASSERTION_LIFETIME,
+        });
+        jsonwebtoken::encode(&header, &claims, &self.key).context("failed to sign client assertion")
+    }
+}
+
+/// App registration and the endpoints a session signs in against.
+#[derive(Debug, Clone)]
+pub struct ODriveConfig {
+    pub provider: Provider,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    /// rfc 7009 endpoint to revoke refresh tokens on logout, microsoft has none
+    pub revocation_url: Option<String>,
+    /// identity platform host, national clouds have their own; for other
+    /// providers one host serving all their oauth endpoints
+    pub authority: Option<String>,
+    /// `common`, `organizations`, `consumers`, or a tenant id or domain
+    pub tenant: String,
+    /// Graph api base, including the version
+    pub graph_url: String,
+    pub scopes: Vec<String>,
+    /// serve the app's own folder (`special/approot`) instead of the drive root
+    pub app_folder: bool,
+    pub drive: DriveTarget,
+    /// sign in as the app itself with client credentials, no user involved
+    pub app_only: bool,
+    /// signs client assertions in place of the client secret
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl ODriveConfig {
+    /// Defaults to Microsoft's global cloud and the `common` tenant.
+    pub fn new(client_id: String, redirect_url: String) -> Self {
+        ODriveConfig {
+            provider: Provider::Microsoft,
+            client_id,
+            client_secret: None,
+            redirect_url,
+            revocation_url: None,
+            authority: None,
+            tenant: DEFAULT_TENANT.to_string(),
+            graph_url: DEFAULT_GRAPH_URL.to_string(),
+            scopes: DEFAULT_SCOPES.iter().map(|s| s.to_string()).collect(),
+            app_folder: false,
+            drive: DriveTarget::Me,
+            app_only: false,
+            client_certificate: None,
+        }
+    }
+
+    /// Sign in with another provider, with its default scopes.
+    pub fn use_provider(&mut self, provider: Provider) {
+        self.provider = provider;
+        self.scopes = provider.default_scopes().iter().map(|s| s.to_string()).collect();
+        if provider == Provider::Google && self.revocation_url.is_none() {
+            self.revocation_url = Some(self.endpoint("https://oauth2.googleapis.com", "/revoke"));
+        }
+    }
+
+    /// Sign in as the app with the application permissions granted to it in
+    /// the tenant. Set after `graph_url`, the scope is derived from it.
+    pub fn use_app_only(&mut self) {
+        self.app_only = true;
+        let resource = Url::parse(&self.graph_url)
+            .map(|url| url.origin().ascii_serialization())
+            .unwrap_or_else(|_| self.graph_url.clone());
+        self.scopes = vec![format!("{}/.default", resource)];
+    }
+
+    /// Only ask for access to the app's folder and serve that, a leaked token
+    /// can't touch the rest of the drive.
+    pub fn use_app_folder(&mut self) {
+        self.app_folder = true;
+        self.scopes = APP_FOLDER_SCOPES.iter().map(|s| s.to_string()).collect();
+    }
+
+    fn endpoint(&self, default_host: &str, path: &str) -> String {
+        format!("{}{}", self.authority.as_deref().unwrap_or(default_host).trim_end_matches('/'), path)
+    }
+
+    fn auth_url(&self) -> String {
+        match self.provider {
+            Provider::Microsoft => self.endpoint(DEFAULT_AUTHORITY, &format!("/{}/oauth2/v2.0/authorize", self.tenant)),
+            Provider::Google => self.endpoint("https://accounts.google.com", "/o/oauth2/v2/auth"),
+            Provider::Dropbox => self.endpoint("https://www.dropbox.com", "/oauth2/authorize"),
+        }
+    }
+
+    fn token_url(&self) -> String {
+        match self.provider {
+            Provider::Microsoft => self.endpoint(DEFAULT_AUTHORITY, &format!("/{}/oauth2/v2.0/token", self.tenant)),
+            Provider::Google => self.endpoint("https://oauth2.googleapis.com", "/token"),
+            Provider::Dropbox => self.endpoint("https://api.dropboxapi.com", "/oauth2/token"),
+        }
+    }
+
+    /// `None` if the provider has no device code login.
+    fn device_url(&self) -> Option<String> {
+        match self.provider {
+            Provider::Microsoft => Some(self.endpoint(DEFAULT_AUTHORITY, &format!("/{}/oauth2/v2.0/devicecode", self.tenant))),
+            Provider::Google => Some(self.endpoint("https://oauth2.googleapis.com", "/device/code")),
+            Provider::Dropbox => None,
+        }
+    }
+
+    fn scopes(&self) -> impl Iterator<Item = Scope> + '_ {
+        self.scopes.iter().map(|s| Scope::new(s.clone()))
+    }
+}
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Me {
@@ -38,6 +291,11 @@ pub struct Me {
 pub struct ODriveSession {
     inner: Arc<Mutex<Inner>>,
     http_client: reqwest::Client,
+    config: Arc<ODriveConfig>,
+    store: Arc<dyn TokenStore>,
+    refresh_margin: Duration,
+    /// reschedules the token thread after a sign in or out
+    wake: Arc<Notify>,
 }
 
 struct Inner {
@@ -48,14 +306,46 @@ struct Inner {
     expires_at: Option<u64>,
     states: BTreeMap<String, PkceCodeVerifier>,
     callbacks: Vec<Box<dyn AsyncHook<ODriveState>>>,
+    signed_out_callbacks: Vec<Box<dyn AsyncHook<String>>>,
+    refresh_status: RefreshStatus,
+    /// set when the tokens were revoked, until the next sign in
+    relogin_required: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct ODriveState {
+    /// kept so a restart doesn't need to refresh while it's still valid
+    #[serde(default)]
+    pub access_token: Option<String>,
     pub refresh_token: Option<String>,
     pub expires_at: Option<u64>,
 }
 
+/// How the background token refresh is doing, times are unix seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RefreshStatus {
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_success: Option<u64>,
+    pub next_refresh: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionStatus {
+    pub signed_in: bool,
+    pub expires_at: Option<u64>,
+    /// why the session was signed out, if it was
+    pub relogin_required: Option<String>,
+    pub refresh: RefreshStatus,
+}
+
+impl SessionStatus {
+    /// Holds an access token that hasn't expired yet.
+    pub fn healthy(&self) -> bool {
+        self.signed_in && self.expires_at.is_some_and(|at| at > now_secs())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenIDFields {
     pub id_token: Option<String>,
@@ -71,7 +361,25 @@ type OpenIDClient = Client<
     BasicTokenIntrospectionResponse,
     StandardRevocableToken,
     BasicRevocationErrorResponse,
-    EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>;
+    EndpointSet, EndpointMaybeSet, EndpointNotSet, EndpointMaybeSet, EndpointSet>;
+
+/// A pending device code login, the user has to enter `user_code` at
+/// `verification_uri` before it expires.
+pub struct DeviceAuth(StandardDeviceAuthorizationResponse);
+
+impl DeviceAuth {
+    pub fn user_code(&self) -> &str {
+        self.0.user_code().secret()
+    }
+
+    pub fn verification_uri(&self) -> &str {
+        self.0.verification_uri()
+    }
+
+    pub fn expires_in(&self) -> Duration {
+        self.0.expires_in()
+    }
+}
 
 #[derive(ThisError, Debug)]
 enum RequestorError {
@@ -84,18 +392,43 @@ enum RequestorError {
 impl ODriveSession {
     pub fn new(
         http_client: reqwest::Client,
-        client_id: String,
-        client_secret: Option<String>,
-        redirect_url: String,
+        config: ODriveConfig,
+        store: Arc<dyn TokenStore>,
     ) -> Result<Self, anyhow::Error> {
+        if config.app_only {
+            anyhow::ensure!(config.provider == Provider::Microsoft, "app-only auth is only supported by microsoft");
+            anyhow::ensure!(!MULTI_TENANTS.contains(&config.tenant.as_str()),
+                "app-only auth needs a tenant id or domain, not {}", config.tenant);
+            anyhow::ensure!(!matches!(config.drive, DriveTarget::Me),
+                "app-only auth has no signed in user, target a user or drive id");
+            anyhow::ensure!(config.client_secret.is_some() != config.client_certificate.is_some(),
+                "app-only auth needs either a client secret or a certificate");
+        }
+        let graph_url = Url::parse(&config.graph_url).with_context(|| format!("invalid graph url {}", config.graph_url))?;
+        anyhow::ensure!(matches!(graph_url.scheme(), "http" | "https") && !graph_url.cannot_be_a_base(),
+            "graph url {} must be an http or https url", config.graph_url);
         // BasicClient::new(client_id)
-        let mut client = Client::new(ClientId::new(client_id))
-            .set_auth_uri(AuthUrl::new(AUTH_URL.to_string())?)
-            .set_token_uri(TokenUrl::new(TOKEN_URL.to_string())?)
-            .set_redirect_uri(RedirectUrl::new(redirect_url)?);
-        if let Some(secret) = client_secret {
+        let mut client = Client::new(ClientId::new(config.client_id.clone()))
+            .set_auth_uri(AuthUrl::new(config.auth_url())?)
+            .set_token_uri(TokenUrl::new(config.token_url())?)
+            .set_device_authorization_url_option(config.device_url().map(DeviceAuthorizationUrl::new).transpose()?)
+            .set_revocation_url_option(config.revocation_url.clone().map(RevocationUrl::new).transpose()?)
+            .set_redirect_uri(RedirectUrl::new(config.redirect_url.clone())?);
+        if let Some(secret) = config.client_secret.clone() {
             client = client.set_client_secret(ClientSecret::new(secret));
         }
+        if config.app_only {
+            client = client.set_auth_type(AuthType::RequestBody);
+        }
+
+        // persist every new token
+        let save_store = store.clone();
+        let save: Box<dyn AsyncHook<ODriveState>> = Box::new(move |state: ODriveState| {
+            let store = save_store.clone();
+            log_and_go(async move {
+                store.save(&state).await
+            })
+        });
 
         Ok(ODriveSession {
             inner: Arc::new(Mutex::new(Inner {
@@ -104,12 +437,50 @@ impl ODriveSession {
                 refresh_token: None,
                 expires_at: None,
                 states: BTreeMap::new(),
-                callbacks: Vec::new(),
+                callbacks: vec![save],
+                signed_out_callbacks: Vec::new(),
+                refresh_status: RefreshStatus::default(),
+                relogin_required: None,
             })),
             http_client,
+            config: Arc::new(config),
+            store,
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            wake: Arc::new(Notify::new()),
         })
     }
 
+    /// Refresh this long before the access token expires.
+    pub fn refresh_margin(mut self, margin: Duration) -> Self {
+        self.refresh_margin = margin;
+        self
+    }
+
+    pub fn provider(&self) -> Provider {
+        self.config.provider
+    }
+
+    /// Gets its tokens with the client credentials, no one signs in.
+    pub fn app_only(&self) -> bool {
+        self.config.app_only
+    }
+
+    /// Can sign in with a device code, without a browser on this machine.
+    pub fn has_device_auth(&self) -> bool {
+        !self.config.app_only && self.config.device_url().is_some()
+    }
+
+    /// Graph api base, including the version.
+    pub fn graph_url(&self) -> &str {
+        self.config.graph_url.trim_end_matches('/')
+    }
+
+    /// The item paths are relative to, the drive root or the app folder.
+    pub fn drive_root_url(&self) -> String {
+        let root = if self.config.app_folder { "special/approot" } else { "root" };
+        format!("{}/{}/{}", self.graph_url(), self.config.drive.path(), root)
+    }
+
     pub async fn initiate_auth(&self) -> Url {
         log::info!("Initiating authentication");
         let mut guard = self.inner.lock().await;
@@ -118,11 +489,14 @@ impl ODriveSession {
         log::debug!("PKCE Verifier: {}", pkce_verifier.secret());
         guard.states.insert(csrftoken.secret().clone(), pkce_verifier);
 
-        let (auth_url, _) = guard.client
+        let mut request = guard.client
             .authorize_url(move || csrftoken)
-            .add_scopes(SCOPES.iter().map(|s| Scope::new(s.to_string())))
-            .set_pkce_challenge(pkce_challenge)
-            .url();
+            .add_scopes(self.config.scopes())
+            .set_pkce_challenge(pkce_challenge);
+        for (name, value) in self.config.provider.offline_params() {
+            request = request.add_extra_param(*name, *value);
+        }
+        let (auth_url, _) = request.url();
 
         auth_url
     }
@@ -146,8 +520,10 @@ impl ODriveSession {
             .request_async(&requestor)
             .await?;
 
-        let id_token = token_result.extra_fields().id_token.as_ref().expect("id_token not present");
-        log::debug!("id_token: {}", id_token);
+        // only there with the openid scope
+        if let Some(id_token) = token_result.extra_fields().id_token.as_ref() {
+            log::debug!("id_token: {}", id_token);
+        }
 
         let (callbacks, state) = {
             let mut guard = self.inner.lock().await;
@@ -155,23 +531,101 @@ impl ODriveSession {
             (guard.callbacks.clone(), guard.state())
         };
         call_on_auth(callbacks, state).await;
+        self.wake.notify_one();
         Ok(())
     }
 
+    pub async fn initiate_device_auth(&self) -> Result<DeviceAuth, AnyError> {
+        log::info!("Initiating device code authentication");
+        anyhow::ensure!(!self.config.app_only, "app-only sessions don't sign in interactively");
+        let client = self.inner.lock().await.client.clone();
+        let requestor = self.requestor();
+        let details: StandardDeviceAuthorizationResponse = client
+            .exchange_device_code()
+            .map_err(|_| anyhow::anyhow!("{} has no device code login", self.config.provider))?
+            .add_scopes(self.config.scopes())
+            .request_async(&requestor)
+            .await?;
+        Ok(DeviceAuth(details))
+    }
+
+    /// Poll until the user completed `device_auth` or it expired.
+    pub async fn device_auth(&self, device_auth: &DeviceAuth) -> Result<(), AnyError> {
+        let client = self.inner.lock().await.client.clone();
+        let requestor = self.requestor();
+        let token_result = client
+            .exchange_device_access_token(&device_auth.0)
+            .request_async(&requestor, sleep, None)
+            .await?;
+        log::info!("Device code authentication successful");
+
+        let (callbacks, state) = {
+            let mut guard = self.inner.lock().await;
+            guard.update_tokens(&token_result)?;
+            (guard.callbacks.clone(), guard.state())
+        };
+        call_on_auth(callbacks, state).await;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Refresh the access token, recording the outcome in the refresh status.
     pub async fn refresh(&self) -> Result<(), AnyError> {
+        let res = self.refresh_token().await;
+        let mut guard = self.inner.lock().await;
+        let status = &mut guard.refresh_status;
+        match &res {
+            Ok(()) => {
+                status.consecutive_failures = 0;
+                status.last_error = None;
+                status.last_success = Some(now_secs());
+            }
+            Err(e) => {
+                status.consecutive_failures += 1;
+                status.last_error = Some(e.to_string());
+            }
+        }
+        res
+    }
+
+    /// Refresh out of schedule, the token thread then plans the next one
+    /// from the new expiry.
+    pub async fn force_refresh(&self) -> Result<(), AnyError> {
+        let res = self.refresh().await;
+        self.wake.notify_one();
+        res
+    }
+
+    async fn refresh_token(&self) -> Result<(), AnyError> {
+        if self.config.app_only {
+            return self.client_credentials().await;
+        }
         log::info!("Refreshing token");
         let (refresh_token, client) = {
             let guard = self.inner.lock().await;
             let refresh_token = guard.refresh_token.clone()
-                .map(|s| RefreshToken::new(s))
+                .map(RefreshToken::new)
                 .context("Refresh token not found")?;
             (refresh_token, guard.client.clone())
         };
         let requestor = self.requestor();
-        let token_result = client
+        let token_result = match client
             .exchange_refresh_token(&refresh_token)
             .request_async(&requestor)
-            .await?;
+            .await
+        {
+            Ok(token_result) => token_result,
+            // the refresh token was revoked or expired, retrying won't help
+            Err(RequestTokenError::ServerResponse(resp)) if *resp.error() == BasicErrorResponseType::InvalidGrant => {
+                let reason = resp.error_description()
+                    .and_then(|d| d.lines().next())
+                    .unwrap_or("refresh token is no longer valid")
+                    .to_string();
+                self.sign_out(Some(reason.clone())).await;
+                return Err(anyhow::anyhow!("re-login required: {}", reason));
+            }
+            Err(e) => return Err(e.into()),
+        };
         let (callbacks, state) = {
             let mut guard = self.inner.lock().await;
             guard.update_tokens(&token_result)?;
@@ -182,12 +636,41 @@ impl ODriveSession {
         Ok(())
     }
 
+    /// Get an app-only token, there is no refresh token so this is repeated
+    /// each time it's about to expire.
+    async fn client_credentials(&self) -> Result<(), AnyError> {
+        log::info!("Requesting app-only token");
+        let client = self.inner.lock().await.client.clone();
+        let mut request = client
+            .exchange_client_credentials()
+            .add_scopes(self.config.scopes());
+        if let Some(certificate) = self.config.client_certificate.as_ref() {
+            let assertion = certificate.assertion(&self.config.client_id, &self.config.token_url())?;
+            request = request
+                .add_extra_param("client_assertion_type", JWT_BEARER_ASSERTION)
+                .add_extra_param("client_assertion", assertion);
+        }
+        let requestor = self.requestor();
+        let token_result = request.request_async(&requestor).await?;
+        let (callbacks, state) = {
+            let mut guard = self.inner.lock().await;
+            guard.update_tokens(&token_result)?;
+            (guard.callbacks.clone(), guard.state())
+        };
+        call_on_auth(callbacks, state).await;
+        log::info!("App-only token acquired");
+        Ok(())
+    }
+
     pub async fn me(&self) -> Result<Option<Me>, AnyError> {
+        let Some(user_path) = self.config.drive.user_path().filter(|_| self.config.provider == Provider::Microsoft) else {
+            return Ok(None);
+        };
         let token = match self.access_token().await {
             Some(t) => t,
             None => return Ok(None),
         };
-        let resp = self.http_client.get("https://graph.microsoft.com/v1.0/me")
+        let resp = self.http_client.get(format!("{}/{}", self.graph_url(), user_path))
             .header("Authorization", format!("Bearer {}", token))
             .send()
             .await?;
@@ -198,12 +681,22 @@ impl ODriveSession {
         self.inner.lock().await.token.clone()
     }
 
+    pub async fn status(&self) -> SessionStatus {
+        let guard = self.inner.lock().await;
+        SessionStatus {
+            signed_in: guard.token.is_some(),
+            expires_at: guard.expires_at,
+            relogin_required: guard.relogin_required.clone(),
+            refresh: guard.refresh_status.clone(),
+        }
+    }
+
     fn requestor(&self) -> impl Fn(HttpRequest) -> Pin<Box<dyn Future<Output = Result<HttpResponse, RequestorError>> + Send>> + use<'_> {
         move |request| {
             let http_client = self.http_client.clone();
             Box::pin(async move {
-                log::debug!("Making HTTP request: {:?} {:?} headers = {:?}, payload = {:?}", 
-                    request.method(), request.uri(), request.headers(), unsafe { String::from_utf8_unchecked(request.body().clone()) });
+                // headers and body carry the client secret or assertion and tokens
+                log::debug!("Making HTTP request: {} {}", request.method(), request.uri());
                 let res = http_client.execute(request.try_into().map_err(RequestorError::HTTPError)?)
                     .await
                     .map_err(RequestorError::HTTPError)?;
@@ -220,16 +713,20 @@ impl ODriveSession {
     }
 
     pub async fn load_token(&self) -> Result<(), anyhow::Error> {
-        // test exists
-        if std::path::Path::new(APP_DATA_PATH).exists() {
-            let data = read_to_string(APP_DATA_PATH).await?;
-            let data: ODriveState = serde_json::from_str(&data).context("failed to deserialize state")?;
-            {
+        if let Some(data) = self.store.load().await? {
+            let valid = data.access_token.is_some() && data.expires_at.is_some_and(|at| at > now_secs());
+            let (callbacks, state) = {
                 let mut guard = self.inner.lock().await;
+                guard.token = data.access_token;
                 guard.refresh_token = data.refresh_token;
                 guard.expires_at = data.expires_at;
+                (guard.callbacks.clone(), guard.state())
+            };
+            log::info!("Loaded token from {}", self.store.describe());
+            // a still valid token is as good as a fresh one
+            if valid {
+                call_on_auth(callbacks, state).await;
             }
-            log::info!("Loaded token from {}", APP_DATA_PATH);
         }
         Ok(())
     }
@@ -242,39 +739,106 @@ impl ODriveSession {
     }
 
     pub async fn token_thread(&self, signal: impl Future<Output=()> + 'static + Send + Clone) {
-        self.on_auth(Box::new(move |state: ODriveState| {
-            log_and_go(async move {
-                let state_json = serde_json::to_string(&state).context("failed to serialize state")?;
-                File::create("app_data.json").await?.write_all(state_json.as_bytes()).await?;
-                anyhow::Ok(())
-            })
-        })).await;
         log_and_go(self.load_token()).await;
-        log_and_go(self.refresh()).await;
-        let mut refresh_sec = 300;
         loop {
+            let delay = self.refresh_delay().await;
             {
-                let guard = self.inner.lock().await;
-                if let Some(expires_at) = guard.expires_at {
-                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u64;
-                    if expires_at > now {
-                        refresh_sec = 0.max(expires_at - now - 60); // refresh 1 min before expiry
-                    } else {
-                        refresh_sec = 0;
-                    }
-                }
+                let mut guard = self.inner.lock().await;
+                guard.refresh_status.next_refresh = delay.map(|d| now_secs() + d.as_secs());
             }
-            log::info!("Next token refresh in {} seconds", refresh_sec);
-            if refresh_sec > 0 {
+            let Some(delay) = delay else {
+                log::debug!("Not signed in, no token to refresh");
                 tokio::select!{
-                    _ = sleep(Duration::from_secs(refresh_sec)) => {},
-                    _ = signal.clone() => { 
+                    _ = sleep(IDLE_POLL) => continue,
+                    _ = self.wake.notified() => continue,
+                    _ = signal.clone() => {
                         log::info!("shutdown signal received, exiting token thread");
                         return
                     },
                 }
+            };
+            log::info!("Next token refresh in {} seconds", delay.as_secs());
+            tokio::select!{
+                _ = sleep(delay) => {},
+                // signed in or out meanwhile, the schedule is stale
+                _ = self.wake.notified() => continue,
+                _ = signal.clone() => {
+                    log::info!("shutdown signal received, exiting token thread");
+                    return
+                },
+            }
+            if let Err(e) = self.refresh().await {
+                log::error!("Token refresh failed: {}", e);
             }
-            log_and_go(self.refresh()).await;
+        }
+    }
+
+    /// Time until the next refresh attempt, `None` without a refresh token
+    /// unless the session is app-only. Failed attempts back off
+    /// exponentially, with jitter.
+    async fn refresh_delay(&self) -> Option<Duration> {
+        let guard = self.inner.lock().await;
+        if !self.config.app_only {
+            guard.refresh_token.as_ref()?;
+        }
+        let failures = guard.refresh_status.consecutive_failures;
+        if failures > 0 {
+            let backoff = BACKOFF_BASE.saturating_mul(1 << (failures - 1).min(16)).min(BACKOFF_MAX);
+            return Some(backoff.mul_f64(rand::random_range(0.5..1.0)));
+        }
+        match (guard.token.as_ref(), guard.expires_at) {
+            (Some(_), Some(expires_at)) => {
+                let remaining = Duration::from_secs(expires_at.saturating_sub(now_secs()));
+                Some(remaining.saturating_sub(self.refresh_margin))
+            }
+            (Some(_), None) => Some(UNKNOWN_EXPIRY_REFRESH),
+            (None, _) => Some(Duration::ZERO),
+        }
+    }
+
+    /// Revoke the refresh token if the provider supports it, then sign out.
+    /// App-only sessions get a new token right away.
+    pub async fn logout(&self) {
+        let (refresh_token, client) = {
+            let guard = self.inner.lock().await;
+            (guard.refresh_token.clone(), guard.client.clone())
+        };
+        if let Some(refresh_token) = refresh_token {
+            match client.revoke_token(RefreshToken::new(refresh_token).into()) {
+                Ok(request) => {
+                    let requestor = self.requestor();
+                    match request.request_async(&requestor).await {
+                        Ok(()) => log::info!("Refresh token revoked"),
+                        Err(e) => log::warn!("failed to revoke refresh token: {}", e),
+                    }
+                }
+                Err(_) => log::info!("No revocation endpoint, only forgetting the tokens"),
+            }
+        }
+        self.sign_out(None).await;
+    }
+
+    /// Forget all tokens, here and in the store, and tell the signed out hooks.
+    /// `relogin_required` tells why if it wasn't on request.
+    async fn sign_out(&self, relogin_required: Option<String>) {
+        let reason = match relogin_required.as_ref() {
+            Some(why) => format!("re-login required: {}", why),
+            None => "signed out".to_string(),
+        };
+        log::warn!("{} session {}", self.config.provider, reason);
+        let callbacks = {
+            let mut guard = self.inner.lock().await;
+            guard.token = None;
+            guard.refresh_token = None;
+            guard.expires_at = None;
+            guard.relogin_required = relogin_required;
+            guard.refresh_status = RefreshStatus::default();
+            guard.signed_out_callbacks.clone()
+        };
+        self.wake.notify_one();
+        log_and_go(self.store.clear()).await;
+        for cb in callbacks.iter() {
+            cb.call(reason.clone()).await;
         }
     }
 
@@ -282,6 +846,12 @@ impl ODriveSession {
         let mut guard = self.inner.lock().await;
         guard.callbacks.push(cb);
     }
+
+    /// Called with the reason when the session loses its tokens for good.
+    pub async fn on_signed_out(&self, cb: Box<dyn AsyncHook<String>>) {
+        let mut guard = self.inner.lock().await;
+        guard.signed_out_callbacks.push(cb);
+    }
 }
 
 async fn call_on_auth(callbacks: Vec<Box<dyn AsyncHook<ODriveState>>>, state: ODriveState) {
@@ -293,16 +863,27 @@ async fn call_on_auth(callbacks: Vec<Box<dyn AsyncHook<ODriveState>>>, state: OD
 impl Inner {
     fn update_tokens(&mut self, token_result: &OpenIDTokenResponse) -> Result<(), std::time::SystemTimeError> {
         self.token = Some(token_result.access_token().secret().clone());
-        self.refresh_token = token_result.refresh_token().map(|t| t.secret().clone());
-        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u64;
+        // refresh responses may leave out the refresh token, keep using the old one then
+        if let Some(refresh_token) = token_result.refresh_token() {
+            self.refresh_token = Some(refresh_token.secret().clone());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
         self.expires_at = token_result.expires_in().map(|d| d.as_secs() + now);
+        self.relogin_required = None;
+        self.refresh_status.consecutive_failures = 0;
+        self.refresh_status.last_error = None;
         Ok(())
     }
 
     fn state(&self) -> ODriveState {
         ODriveState {
+            access_token: self.token.clone(),
             refresh_token: self.refresh_token.clone(),
             expires_at: self.expires_at,
         }
     }
 }
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
@@ -1,8 +1,15 @@
-use axum::{Json, Router, extract::{Query, State}, response::Redirect, routing::{get, post}};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use axum::{Json, Router, extract::{Query, State}, middleware, response::Redirect, routing::{get, post}};
 use http::StatusCode;
 use serde::Deserialize;
 
-use crate::odrive::{Me, ODriveSession};
+use crate::account::AccountInfo;
+use crate::basic_auth::{require_user, BasicAuth};
+use crate::odrive::{Me, ODriveSession, SessionStatus};
+use crate::types::Response;
 
 // Struct to receive the query parameters
 #[derive(Deserialize)]
@@ -12,10 +19,10 @@ struct CallbackQuery {
 }
 
 #[derive(serde::Serialize)]
-struct Response<T> where T: serde::Serialize {
-    code: u16,
-    msg: String,
-    body: T,
+struct DeviceLogin {
+    user_code: String,
+    verification_uri: String,
+    expires_in: u64,
 }
 
 async fn login(State(session): State<ODriveSession>) -> Redirect {
@@ -37,6 +44,34 @@ async fn callback(State(session): State<ODriveSession>, Query(query): Query<Call
     }
 }
 
+/// Starts a device code login and polls for its completion in the
+/// background, the user finishes it at `verification_uri`.
+async fn device_login(State(session): State<ODriveSession>) -> (StatusCode, Json<Response<Option<DeviceLogin>>>) {
+    let device_auth = match session.initiate_device_auth().await {
+        Ok(device_auth) => device_auth,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(Response {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            msg: format!("error initiating device login: {}", e),
+            body: None,
+        })),
+    };
+    let body = DeviceLogin {
+        user_code: device_auth.user_code().to_string(),
+        verification_uri: device_auth.verification_uri().to_string(),
+        expires_in: device_auth.expires_in().as_secs(),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = session.device_auth(&device_auth).await {
+            log::error!("Device code authentication failed: {}", e);
+        }
+    });
+    (StatusCode::OK, Json(Response {
+        code: StatusCode::OK.as_u16(),
+        msg: "success".to_string(),
+        body: Some(body),
+    }))
+}
+
 async fn me(State(session): State<ODriveSession>) -> (StatusCode, Json<Response<Option<Me>>>) {
     match session.me().await {
         Ok(Some(info)) => (StatusCode::OK, Json(Response {
@@ -57,10 +92,116 @@ async fn me(State(session): State<ODriveSession>) -> (StatusCode, Json<Response<
     }
 }
 
-pub fn onedrive_api_router(session: ODriveSession) -> Router {
+/// Disconnects the account: revokes what can be revoked and forgets the
+/// tokens, the dav service stays down until the next sign in.
+async fn logout(State(session): State<ODriveSession>) -> Json<Response<()>> {
+    session.logout().await;
+    Json(Response {
+        code: StatusCode::OK.as_u16(),
+        msg: "signed out".to_string(),
+        body: (),
+    })
+}
+
+async fn status(State(session): State<ODriveSession>) -> Json<Response<SessionStatus>> {
+    Json(Response {
+        code: StatusCode::OK.as_u16(),
+        msg: "success".to_string(),
+        body: session.status().await,
+    })
+}
+
+/// Refreshes the access token now, instead of waiting for it to expire.
+async fn refresh(State(session): State<ODriveSession>) -> (StatusCode, Json<Response<SessionStatus>>) {
+    let (code, msg) = match session.force_refresh().await {
+        Ok(()) => (StatusCode::OK, "success".to_string()),
+        Err(e) => (StatusCode::BAD_GATEWAY, format!("error refreshing token: {}", e)),
+    };
+    (code, Json(Response {
+        code: code.as_u16(),
+        msg,
+        body: session.status().await,
+    }))
+}
+
+/// Why the session can't serve, `None` while it holds a valid access token.
+fn unhealthy(status: &SessionStatus) -> Option<String> {
+    if status.healthy() {
+        None
+    } else if let Some(reason) = status.relogin_required.as_ref() {
+        Some(format!("re-login required: {}", reason))
+    } else if let Some(e) = status.refresh.last_error.as_ref() {
+        Some(format!("token refresh failing: {}", e))
+    } else {
+        Some("not signed in".to_string())
+    }
+}
+
+/// 200 while the session holds a valid access token, 503 otherwise.
+pub async fn health(State(session): State<ODriveSession>) -> (StatusCode, Json<Response<SessionStatus>>) {
+    let status = session.status().await;
+    let (code, msg) = match unhealthy(&status) {
+        None => (StatusCode::OK, "healthy".to_string()),
+        Some(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
+    };
+    (code, Json(Response {
+        code: code.as_u16(),
+        msg,
+        body: status,
+    }))
+}
+
+/// [`health`] of several named accounts, 503 unless all are healthy.
+pub async fn accounts_health(State(sessions): State<Vec<(String, ODriveSession)>>) -> (StatusCode, Json<Response<BTreeMap<String, SessionStatus>>>) {
+    let mut problems = Vec::new();
+    let mut body = BTreeMap::new();
+    for (name, session) in sessions.iter() {
+        let status = session.status().await;
+        if let Some(msg) = unhealthy(&status) {
+            problems.push(format!("{}: {}", name, msg));
+        }
+        body.insert(name.clone(), status);
+    }
+    let (code, msg) = match problems.is_empty() {
+        true => (StatusCode::OK, "healthy".to_string()),
+        false => (StatusCode::SERVICE_UNAVAILABLE, problems.join("; ")),
+    };
+    (code, Json(Response {
+        code: code.as_u16(),
+        msg,
+        body,
+    }))
+}
+
+async fn accounts(State(accounts): State<Arc<ArcSwap<Vec<AccountInfo>>>>) -> Json<Response<Vec<AccountInfo>>> {
+    Json(Response {
+        code: StatusCode::OK.as_u16(),
+        msg: "success".to_string(),
+        body: accounts.load().to_vec(),
+    })
+}
+
+/// Lists the accounts, where they're mounted and where to sign them in.
+/// The mounts change on reload.
+pub fn accounts_api_router(infos: Arc<ArcSwap<Vec<AccountInfo>>>) -> Router {
     Router::new()
+        .route("/", get(accounts))
+        .with_state(infos)
+}
+
+/// Signing in, out or refreshing takes one of the users, the callback is
+/// bound to a sign in by its state.
+pub fn onedrive_api_router(session: ODriveSession, auth: BasicAuth) -> Router {
+    let account = Router::new()
         .route("/login", post(login))
+        .route("/device_login", post(device_login))
+        .route("/logout", post(logout))
+        .route("/refresh", post(refresh))
+        .route_layer(middleware::from_fn_with_state(auth, require_user));
+    Router::new()
         .route("/callback", get(callback))
         .route("/me", get(me))
+        .route("/status", get(status))
+        .merge(account)
         .with_state(session)
 }
\ No newline at end of file
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::account::AccountInfo;
+use crate::basic_auth::{BasicAuth, Users};
+use crate::config::{Config, ServerConfig};
+use crate::file_ls::FileLs;
+use crate::mounts::{LayerPool, Mounts};
+use crate::odrive::ODriveSession;
+
+/// Swaps the filter of the running subscriber.
+pub type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// The filter `log_level` asks for, `None` while `RUST_LOG` overrides it.
+pub fn log_filter(server: &ServerConfig) -> Option<EnvFilter> {
+    if std::env::var_os("RUST_LOG").is_some() {
+        return None;
+    }
+    Some(EnvFilter::new(server.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL)))
+}
+
+/// Rebuilds what changed when the config is reloaded, and keeps the rest:
+/// the sessions, the lock system, and the mounts whose config didn't
+/// change along with their requests in flight.
+///
+/// Mounts are rebuilt when their config changed, or only retuned when just
+/// their cache sizes or mux files did. The dav users are read again, along
+/// with their htpasswd file. The accounts, the server's address, url and
+/// lock file, and the app passwords file are only read at startup.
+pub struct Reloader {
+    /// re-read on reload, the env vars without one
+    path: Option<PathBuf>,
+    config: Config,
+    sessions: BTreeMap<String, ODriveSession>,
+    locks: FileLs,
+    pool: LayerPool,
+    mounts: Mounts,
+    accounts: Arc<ArcSwap<Vec<AccountInfo>>>,
+    auth: BasicAuth,
+    log_filter: Option<LogFilter>,
+}
+
+impl Reloader {
+    /// Nothing is mounted until the first [`Reloader::apply`].
+    pub fn new(path: Option<PathBuf>, config: &Config, sessions: BTreeMap<String, ODriveSession>, locks: FileLs, auth: BasicAuth, log_filter: Option<LogFilter>) -> Self {
+        Reloader {
+            path,
+            config: Config { mounts: Vec::new(), ..config.clone() },
+            sessions,
+            locks,
+            pool: LayerPool::default(),
+            mounts: Mounts::default(),
+            accounts: Arc::new(ArcSwap::from_pointee(Vec::new())),
+            auth,
+            log_filter,
+        }
+    }
+
+    pub fn mounts(&self) -> Mounts {
+        self.mounts.clone()
+    }
+
+    /// The accounts and their current mounts, for the accounts api.
+    pub fn accounts(&self) -> Arc<ArcSwap<Vec<AccountInfo>>> {
+        self.accounts.clone()
+    }
+
+    /// The dav users and their app passwords, for the mounts and the app
+    /// passwords api.
+    pub fn auth(&self) -> BasicAuth {
+        self.auth.clone()
+    }
+
+    /// Re-read the config and apply it, the current one stays if it's
+    /// invalid or a mount fails to build.
+    pub async fn reload(&mut self, signal: impl Future<Output = ()> + Send + Clone + 'static) -> Result<()> {
+        let mut config = match self.path.as_ref() {
+            Some(path) => Config::load(path)?,
+            None => Config::from_env()?,
+        };
+        let (new, current) = (&mut config.server, &self.config.server);
+        if new.bind_addr != current.bind_addr || new.exposed_url != current.exposed_url || new.lock_file != current.lock_file {
+            log::warn!("bind_addr, exposed_url and lock_file changes need a restart");
+            new.bind_addr = current.bind_addr.clone();
+            new.exposed_url = current.exposed_url.clone();
+            new.lock_file = current.lock_file.clone();
+        }
+        if config.auth.app_passwords_file != self.config.auth.app_passwords_file {
+            log::warn!("app_passwords_file changes need a restart");
+            config.auth.app_passwords_file = self.config.auth.app_passwords_file.clone();
+        }
+        if config.accounts != self.config.accounts {
+            log::warn!("account changes need a restart");
+            config.accounts = self.config.accounts.clone();
+        }
+        self.apply(config, signal).await
+    }
+
+    /// Build the mounts that are new or changed first, then swap them all
+    /// in at once.
+    pub async fn apply(&mut self, config: Config, signal: impl Future<Output = ()> + Send + Clone + 'static) -> Result<()> {
+        let users = Users::from_config(&config.auth)?;
+        let current = self.mounts.load();
+        // the server's body limit is baked into the handlers without their own
+        let rebuild_all = config.server.max_body_size != self.config.server.max_body_size;
+        let mut mounts = BTreeMap::new();
+        let mut retune = Vec::new();
+        let mut built = Vec::new();
+        for mount in config.mounts.iter() {
+            match current.get(&mount.prefix) {
+                Some(old) if !rebuild_all && old.config == *mount => {
+                    mounts.insert(mount.prefix.clone(), old.clone());
+                }
+                Some(old) if !rebuild_all && old.config.retunable_to(mount) => {
+                    mounts.insert(mount.prefix.clone(), old.clone());
+                    retune.push(mount);
+                }
+                old => {
+                    let new = crate::build_mount(mount, &self.sessions, &config.server, &self.locks, &self.pool, signal.clone())
+                        .with_context(|| format!("failed to mount {}", mount.prefix))?;
+                    mounts.insert(mount.prefix.clone(), new);
+                    built.push((mount, old.is_some()));
+                }
+            }
+        }
+        // every mount built, nothing can fail from here on
+        for (mount, remounted) in built {
+            let new = &mounts[&mount.prefix];
+            new.activate();
+            let session = mount.backend.account().and_then(|name| self.sessions.get(name));
+            if let Some(session) = session {
+                if session.access_token().await.is_some() {
+                    new.signed_in();
+                }
+            }
+            log::info!("{} {} at {}", if remounted { "remounted" } else { "mounted" }, mount.root, mount.prefix);
+        }
+        for mount in retune {
+            mounts.get_mut(&mount.prefix).unwrap().retune(mount);
+            log::info!("retuned {}", mount.prefix);
+        }
+        for prefix in current.keys().filter(|prefix| !mounts.contains_key(*prefix)) {
+            log::info!("unmounted {}", prefix);
+        }
+        self.mounts.store(mounts);
+        match users.as_ref() {
+            Some(users) => log::info!("dav mounts require one of {} users", users.len()),
+            None => log::warn!("no dav users configured, the mounts are open to anyone"),
+        }
+        self.auth.set(users);
+
+        self.accounts.store(Arc::new(config.accounts.keys()
+            .map(|name| AccountInfo {
+                name: name.clone(),
+                mounts: config.account_mounts(name),
+                api: config.api_prefix(name),
+            })
+            .collect()));
+        if config.server.log_level != self.config.server.log_level {
+            if let (Some(handle), Some(filter)) = (self.log_filter.as_ref(), log_filter(&config.server)) {
+                match handle.reload(filter) {
+                    Ok(()) => log::info!("log level set to {}", config.server.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL)),
+                    Err(e) => log::error!("failed to change the log level: {}", e),
+                }
+            }
+        }
+        self.config = config;
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use http::{header, HeaderValue, Request, Response};
+use opendal::raw::{HttpBody, HttpFetch};
+use opendal::{Buffer, Error, ErrorKind, Result};
+
+use crate::odrive::{ODriveSession, DEFAULT_DRIVE_ROOT_URL, DEFAULT_GRAPH_URL};
+
+/// opendal http client that signs requests with the session's current
+/// access token, so a token refresh doesn't need a new operator. Works for
+/// any oauth backend, the Graph rewrites only touch OneDrive's requests.
+///
+/// Only requests opendal already signed get the token, upload session chunk
+/// requests must go without `Authorization`. opendal always talks to the
+/// global Graph endpoint and the drive root, those requests are sent to the
+/// session's `graph_url` and `drive_root_url` instead.
+pub struct SessionFetch {
+    client: reqwest::Client,
+    session: ODriveSession,
+}
+
+impl SessionFetch {
+    pub fn new(client: reqwest::Client, session: ODriveSession) -> Self {
+        SessionFetch { client, session }
+    }
+}
+
+impl HttpFetch for SessionFetch {
+    async fn fetch(&self, mut req: Request<Buffer>) -> Result<Response<HttpBody>> {
+        let uri = req.uri().to_string();
+        let rewritten = match uri.strip_prefix(DEFAULT_DRIVE_ROOT_URL) {
+            Some(rest) => Some(format!("{}{}", self.session.drive_root_url(), rest)),
+            None => uri.strip_prefix(DEFAULT_GRAPH_URL).map(|rest| format!("{}{}", self.session.graph_url(), rest)),
+        };
+        if let Some(rewritten) = rewritten.filter(|rewritten| *rewritten != uri) {
+            *req.uri_mut() = rewritten.parse()
+                .map_err(|e| Error::new(ErrorKind::Unexpected, "invalid graph url").set_source(e))?;
+        }
+        if req.headers().contains_key(header::AUTHORIZATION) {
+            if let Some(token) = self.session.access_token().await {
+                match HeaderValue::from_str(&format!("Bearer {}", token)) {
+                    Ok(value) => { req.headers_mut().insert(header::AUTHORIZATION, value); }
+                    Err(e) => log::error!("access token is not a valid header value: {}", e),
+                }
+            }
+        }
+        self.client.fetch(req).await
+    }
+}
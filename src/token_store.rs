@@ -0,0 +1,388 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::odrive::ODriveState;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+/// Where a session persists its tokens between runs.
+pub trait TokenStore: Send + Sync {
+    /// The saved state, `None` if nothing was saved yet.
+    fn load(&self) -> StoreFuture<'_, Option<ODriveState>>;
+
+    fn save<'a>(&'a self, state: &'a ODriveState) -> StoreFuture<'a, ()>;
+
+    /// Forget the saved state, once it's no good anymore.
+    fn clear(&self) -> StoreFuture<'_, ()>;
+
+    /// Shown in logs.
+    fn describe(&self) -> String;
+}
+
+/// Plaintext json file, replaced atomically and only readable by the owner.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileStore { path: path.into() }
+    }
+
+    async fn read(&self) -> anyhow::Result<Option<Stored>> {
+        let data = match tokio::fs::read(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", self.path.display())),
+        };
+        restrict_permissions(&self.path).await?;
+        Ok(Some(serde_json::from_slice(&data).context("failed to deserialize state")?))
+    }
+
+    /// Replace the file with `data`: write a temp file, fsync, rename.
+    async fn write(&self, data: &[u8]) -> anyhow::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&tmp).await
+            .with_context(|| format!("failed to create {}", tmp.display()))?;
+        // the temp file may predate us with a wider mode
+        restrict_permissions(&tmp).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, &self.path).await
+            .with_context(|| format!("failed to replace {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+impl TokenStore for FileStore {
+    fn load(&self) -> StoreFuture<'_, Option<ODriveState>> {
+        Box::pin(async move {
+            match self.read().await? {
+                None => Ok(None),
+                Some(Stored::Plain(state)) => Ok(Some(state)),
+                Some(Stored::Sealed(_)) => anyhow::bail!("{} is encrypted but no token key is configured", self.path.display()),
+            }
+        })
+    }
+
+    fn save<'a>(&'a self, state: &'a ODriveState) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            self.write(&serde_json::to_vec(state).context("failed to serialize state")?).await
+        })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(&self.path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("failed to remove {}", self.path.display()))
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// A [`FileStore`] sealed with XChaCha20-Poly1305, the key being the sha256
+/// of whatever key material was given. A plaintext file found in its place
+/// is loaded and rewritten encrypted.
+#[derive(Clone)]
+pub struct EncryptedFileStore {
+    file: FileStore,
+    key: [u8; 32],
+}
+
+impl std::fmt::Debug for EncryptedFileStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedFileStore").field("path", &self.file.path).finish()
+    }
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: impl Into<PathBuf>, key_material: &[u8]) -> Self {
+        EncryptedFileStore {
+            file: FileStore::new(path),
+            key: Sha256::digest(key_material).into(),
+        }
+    }
+}
+
+impl TokenStore for EncryptedFileStore {
+    fn load(&self) -> StoreFuture<'_, Option<ODriveState>> {
+        Box::pin(async move {
+            match self.file.read().await? {
+                None => Ok(None),
+                Some(Stored::Sealed(sealed)) => Ok(Some(open(&self.key, &sealed)?)),
+                Some(Stored::Plain(state)) => {
+                    log::info!("encrypting plaintext token file {}", self.file.path.display());
+                    self.save(&state).await?;
+                    Ok(Some(state))
+                }
+            }
+        })
+    }
+
+    fn save<'a>(&'a self, state: &'a ODriveState) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let data = serde_json::to_vec(state).context("failed to serialize state")?;
+            let sealed = seal(&self.key, &data)?;
+            self.file.write(&serde_json::to_vec(&sealed).context("failed to serialize sealed state")?).await
+        })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        self.file.clear()
+    }
+
+    fn describe(&self) -> String {
+        format!("{} (encrypted)", self.file.path.display())
+    }
+}
+
+/// Read-only credential provisioned from outside, a `*_FILE` env var or a
+/// systemd credential in `$CREDENTIALS_DIRECTORY`. Holds either a json state
+/// or a bare refresh token.
+///
+/// Refreshed tokens are never written back, they only live in memory.
+#[derive(Debug, Clone)]
+pub struct CredentialStore {
+    path: PathBuf,
+}
+
+impl CredentialStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        CredentialStore { path: path.into() }
+    }
+
+    /// `$<var>_FILE` if set, otherwise the systemd credential `name` if present.
+    pub fn locate(var: &str, name: &str) -> Option<Self> {
+        if let Ok(path) = std::env::var(format!("{}_FILE", var)) {
+            return Some(CredentialStore::new(path));
+        }
+        let path = Path::new(&std::env::var_os("CREDENTIALS_DIRECTORY")?).join(name);
+        path.exists().then(|| CredentialStore::new(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl TokenStore for CredentialStore {
+    fn load(&self) -> StoreFuture<'_, Option<ODriveState>> {
+        Box::pin(async move {
+            let data = tokio::fs::read_to_string(&self.path).await
+                .with_context(|| format!("failed to read credential {}", self.path.display()))?;
+            let data = data.trim();
+            if data.starts_with('{') {
+                return Ok(Some(serde_json::from_str(data).context("failed to deserialize state")?));
+            }
+            Ok(Some(ODriveState {
+                access_token: None,
+                refresh_token: Some(data.to_string()),
+                expires_at: None,
+            }))
+        })
+    }
+
+    fn save<'a>(&'a self, _state: &'a ODriveState) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            log::debug!("credential {} is read-only, not saving state", self.path.display());
+            Ok(())
+        })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            log::warn!("credential {} is read-only, replace it to sign in again", self.path.display());
+            Ok(())
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("{} (read-only)", self.path.display())
+    }
+}
+
+/// Keeps the state for the lifetime of the process only.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<Option<ODriveState>>,
+}
+
+impl TokenStore for MemoryStore {
+    fn load(&self) -> StoreFuture<'_, Option<ODriveState>> {
+        let state = self.state.lock().unwrap().clone();
+        Box::pin(async move { Ok(state) })
+    }
+
+    fn save<'a>(&'a self, state: &'a ODriveState) -> StoreFuture<'a, ()> {
+        *self.state.lock().unwrap() = Some(state.clone());
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        *self.state.lock().unwrap() = None;
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn describe(&self) -> String {
+        "memory".to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    // tried first, a plain state would match any object
+    Sealed(Sealed),
+    Plain(ODriveState),
+}
+
+fn seal(key: &[u8; 32], plaintext: &[u8]) -> anyhow::Result<Sealed> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow::anyhow!("failed to encrypt state"))?;
+    Ok(Sealed {
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+fn open(key: &[u8; 32], sealed: &Sealed) -> anyhow::Result<ODriveState> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = BASE64.decode(&sealed.nonce).context("invalid nonce")?;
+    anyhow::ensure!(nonce.len() == 24, "invalid nonce length");
+    let ciphertext = BASE64.decode(&sealed.ciphertext).context("invalid ciphertext")?;
+    let plaintext = cipher.decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| anyhow::anyhow!("failed to decrypt state, wrong token key?"))?;
+    serde_json::from_slice(&plaintext).context("failed to deserialize state")
+}
+
+/// Narrow `path` to 0600 if others can read it.
+#[cfg(unix)]
+pub(crate) async fn restrict_permissions(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = tokio::fs::metadata(path).await?.permissions().mode();
+    if mode & 0o077 != 0 {
+        log::warn!("tightening permissions of {} from {:o} to 600", path.display(), mode & 0o777);
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn restrict_permissions(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{Json, Router, routing::post};
+
+    use super::*;
+    use crate::odrive::{ODriveConfig, ODriveSession};
+
+    fn state(refresh_token: &str) -> ODriveState {
+        ODriveState {
+            access_token: Some("access".to_string()),
+            refresh_token: Some(refresh_token.to_string()),
+            expires_at: Some(u64::MAX / 2),
+        }
+    }
+
+    /// A token endpoint handing out `fresh` for any refresh token.
+    async fn token_server() -> String {
+        let app = Router::new().route("/{tenant}/oauth2/v2.0/token", post(|| async {
+            Json(serde_json::json!({
+                "access_token": "fresh",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "refresh_token": "next",
+            }))
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn session_saves_loads_and_clears_memory_store() {
+        let store = Arc::new(MemoryStore::default());
+        store.save(&state("saved")).await.unwrap();
+        let mut config = ODriveConfig::new("client".to_string(), "http://localhost/callback".to_string());
+        config.authority = Some(token_server().await);
+        let session = ODriveSession::new(reqwest::Client::new(), config, store.clone()).unwrap();
+
+        session.load_token().await.unwrap();
+        assert_eq!(session.access_token().await.as_deref(), Some("access"));
+
+        session.refresh().await.unwrap();
+        let saved = store.load().await.unwrap().expect("refreshed tokens are saved");
+        assert_eq!(saved.access_token.as_deref(), Some("fresh"));
+        assert_eq!(saved.refresh_token.as_deref(), Some("next"));
+
+        session.logout().await;
+        assert!(store.load().await.unwrap().is_none());
+        assert!(session.access_token().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn encrypted_store_migrates_plaintext_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app_data.json");
+        FileStore::new(&path).save(&state("plain")).await.unwrap();
+
+        let store = EncryptedFileStore::new(&path, b"key");
+        let loaded = store.load().await.unwrap().unwrap();
+        assert_eq!(loaded.refresh_token.as_deref(), Some("plain"));
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("plain"), "the file is rewritten encrypted");
+        assert!(matches!(serde_json::from_str(&data).unwrap(), Stored::Sealed(_)));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let loaded = store.load().await.unwrap().unwrap();
+        assert_eq!(loaded.refresh_token.as_deref(), Some("plain"));
+        assert!(EncryptedFileStore::new(&path, b"other").load().await.is_err());
+        assert!(FileStore::new(&path).load().await.is_err());
+
+        store.clear().await.unwrap();
+        assert!(store.load().await.unwrap().is_none());
+    }
+}
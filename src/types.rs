@@ -1,4 +1,3 @@
-
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("Reqwest error: {0}")]
@@ -11,13 +10,10 @@ pub(crate) fn plain_error<S: ToString>(msg: S) -> impl FnOnce() -> AppError {
     move || { AppError::PlainError(msg.to_string()) }
 }
 
-
-#[derive(Debug, Clone, Default)]
-pub struct OneDriveArgs {
-    pub onedrive_root: String,
-    pub client_id: String,
-    pub client_secret: Option<String>,
-    pub access_token: Option<String>,
-    pub refresh_token: Option<String>,
-    pub expires_in: Option<u64>,
-}
\ No newline at end of file
+/// json envelope of the api endpoints
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Response<T> {
+    pub code: u16,
+    pub msg: String,
+    pub body: T,
+}
@@ -2,37 +2,48 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use http::{Request, StatusCode};
-use tokio::sync::Mutex;
 use tower_service::Service;
 use axum::{body::Body, response::IntoResponse};
 
+/// Answers 503 until a service is installed with `init`, and again after
+/// `reset`.
+///
+/// The current service is swapped atomically and cloned per request, so
+/// requests run concurrently and in-flight ones finish on the service they
+/// started with.
 #[derive(Clone)]
 pub struct UninitSvc<S> {
-    inner: Arc<Mutex<UninitSvcInner<S>>>,
+    inner: Arc<ArcSwap<UninitSvcInner<S>>>,
 }
 
 pub enum UninitSvcInner<S> {
-    Uninit,
+    /// why there's no service, sent along with the 503
+    Uninit(String),
     Inited(S),
 }
 
 impl<S> UninitSvc<S> {
     pub fn new() -> Self {
         UninitSvc {
-            inner: Arc::new(Mutex::new(UninitSvcInner::Uninit)),
+            inner: Arc::new(ArcSwap::from_pointee(UninitSvcInner::Uninit("Service not initialized".to_string()))),
         }
     }
 
-    pub async fn init(&self, svc: S) {
-        let mut guard = self.inner.lock().await;
-        *guard = UninitSvcInner::Inited(svc);
+    pub fn init(&self, svc: S) {
+        self.inner.store(Arc::new(UninitSvcInner::Inited(svc)));
+    }
+
+    /// Drop the service, answering 503 with `reason` until the next `init`.
+    pub fn reset(&self, reason: impl Into<String>) {
+        self.inner.store(Arc::new(UninitSvcInner::Uninit(reason.into())));
     }
 }
 
 impl<S> Service<Request<Body>> for UninitSvc<S>
 where
-    S: Service<Request<Body>> + Send + 'static,
+    S: Service<Request<Body>> + Clone + Send + 'static,
     S::Future: Send,
     S::Response: IntoResponse,
 {
@@ -40,20 +51,22 @@ where
     type Error = S::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
         Ok(()).into()
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let inner = self.inner.clone();
+        let mut svc = match &**self.inner.load() {
+            UninitSvcInner::Uninit(reason) => {
+                let reason = reason.clone();
+                return Box::pin(async move {
+                    Ok((StatusCode::SERVICE_UNAVAILABLE, reason).into_response())
+                });
+            }
+            UninitSvcInner::Inited(svc) => svc.clone(),
+        };
         Box::pin(async move {
-            let res = match &mut *inner.lock().await {
-                UninitSvcInner::Uninit => Ok((StatusCode::SERVICE_UNAVAILABLE, "Service not initialized").into_response()),
-                UninitSvcInner::Inited(svc) => svc.call(req).await.map(|resp| {
-                    resp.into_response()
-                }),
-            };
-            res
+            svc.call(req).await.map(|resp| resp.into_response())
         })
     }
 }
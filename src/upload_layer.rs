@@ -0,0 +1,509 @@
+use std::collections::HashSet;
+use std::io::SeekFrom;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BufMut;
+use oauth2::url::Url;
+use opendal::raw::{oio, parse_datetime_from_rfc3339, Access, Layer, LayeredAccess, OpList, OpRead, OpWrite, RpDelete, RpList, RpRead, RpWrite};
+use opendal::{EntryMode, Error, ErrorKind, Metadata, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::odrive::ODriveSession;
+
+/// Graph only accepts simple uploads up to 4 MiB, larger files go through upload sessions.
+const SIMPLE_UPLOAD_LIMIT: usize = 4 * 1024 * 1024;
+/// Upload session chunks must be a multiple of 320 KiB.
+const CHUNK_ALIGN: usize = 320 * 1024;
+const DEFAULT_CHUNK_SIZE: usize = CHUNK_ALIGN * 32; // 10 MiB
+const MAX_CHUNK_ATTEMPTS: u32 = 5;
+
+/// Sends large writes to OneDrive through Graph upload sessions.
+///
+/// Writes are staged into `state_dir` next to a small json file recording the
+/// session URL and how far the upload got, so an interrupted upload can pick
+/// up where it stopped via [`UploadSessionLayer::spawn_resume`].
+/// Small files still go through the inner writer.
+#[derive(Clone)]
+pub struct UploadSessionLayer {
+    uploader: Arc<Uploader>,
+}
+
+impl UploadSessionLayer {
+    pub fn new(session: ODriveSession, http_client: reqwest::Client, onedrive_root: &str, state_dir: impl Into<PathBuf>) -> Self {
+        UploadSessionLayer {
+            uploader: Arc::new(Uploader {
+                session,
+                http_client,
+                root: onedrive_root.trim_matches('/').to_string(),
+                state_dir: state_dir.into(),
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                active: std::sync::Mutex::new(HashSet::new()),
+            }),
+        }
+    }
+
+    /// Rounded down to a multiple of 320 KiB as required by Graph.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        let aligned = (chunk_size / CHUNK_ALIGN).max(1) * CHUNK_ALIGN;
+        if aligned != chunk_size {
+            log::warn!("upload chunk size {} is not a multiple of {}, using {}", chunk_size, CHUNK_ALIGN, aligned);
+        }
+        Arc::get_mut(&mut self.uploader)
+            .expect("chunk_size must be set before the layer is used")
+            .chunk_size = aligned;
+        self
+    }
+
+    /// Resume uploads left behind by a restart or a failed write, and drop
+    /// whatever can't be resumed.
+    pub fn spawn_resume(&self) {
+        let uploader = self.uploader.clone();
+        tokio::spawn(async move {
+            if let Err(e) = uploader.resume_pending().await {
+                log::error!("failed to resume pending uploads: {}", e);
+            }
+        });
+    }
+}
+
+impl<A: Access> Layer<A> for UploadSessionLayer {
+    type LayeredAccess = UploadSessionAccess<A>;
+
+    fn layer(&self, access: A) -> Self::LayeredAccess {
+        UploadSessionAccess { access, uploader: self.uploader.clone() }
+    }
+}
+
+pub struct UploadSessionAccess<A> where A: Access {
+    access: A,
+    uploader: Arc<Uploader>,
+}
+
+impl<A: Access> std::fmt::Debug for UploadSessionAccess<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UploadSessionAccess").field("access", &self.access).finish()
+    }
+}
+
+impl<A: Access> LayeredAccess for UploadSessionAccess<A> {
+    type Inner = A;
+    type Reader = A::Reader;
+    type Writer = UploadWriter<A::Writer>;
+    type Lister = A::Lister;
+    type Deleter = A::Deleter;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.access
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+        self.access.read(path, args).await
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+        let (rp_write, writer) = self.access.write(path, args).await?;
+        Ok((rp_write, UploadWriter {
+            inner: Some(writer),
+            uploader: self.uploader.clone(),
+            path: path.to_string(),
+            buffer: Vec::new(),
+            staged: None,
+            size: 0,
+            committed: false,
+        }))
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Lister)> {
+        self.access.list(path, args).await
+    }
+
+    async fn delete(&self) -> Result<(RpDelete, Self::Deleter)> {
+        self.access.delete().await
+    }
+}
+
+/// Persisted progress of one upload, stored as `<id>.json` beside `<id>.data`.
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadState {
+    path: String,
+    size: u64,
+    upload_url: Option<String>,
+    next_offset: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadSessionResponse {
+    upload_url: Option<String>,
+    #[serde(default)]
+    next_expected_ranges: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DriveItem {
+    e_tag: Option<String>,
+    size: Option<u64>,
+    last_modified_date_time: Option<String>,
+}
+
+struct Staged {
+    id: String,
+    file: File,
+    _claim: Claim,
+}
+
+pub struct UploadWriter<W> {
+    /// until the write is staged, the upload session takes over from there
+    inner: Option<W>,
+    uploader: Arc<Uploader>,
+    path: String,
+    buffer: Vec<u8>,
+    staged: Option<Staged>,
+    size: u64,
+    committed: bool,
+}
+
+impl<W: oio::Write> oio::Write for UploadWriter<W> {
+    async fn write(&mut self, bs: opendal::Buffer) -> Result<()> {
+        self.size += bs.len() as u64;
+        if self.staged.is_none() && self.buffer.len() + bs.len() <= SIMPLE_UPLOAD_LIMIT {
+            self.buffer.put(bs);
+            return Ok(());
+        }
+        let staged = match &mut self.staged {
+            Some(staged) => staged,
+            None => {
+                let mut staged = self.uploader.stage().await?;
+                log::debug!("stage {} as upload {}", self.path, staged.id);
+                if let Some(mut inner) = self.inner.take() {
+                    inner.abort().await?;
+                }
+                staged.file.write_all(&mem::take(&mut self.buffer)).await.map_err(io_error)?;
+                self.staged.insert(staged)
+            }
+        };
+        for chunk in bs {
+            staged.file.write_all(&chunk).await.map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<Metadata> {
+        let Some(staged) = self.staged.as_mut() else {
+            let inner = self.inner.as_mut().expect("inner writer is kept until staged");
+            inner.write(mem::take(&mut self.buffer).into()).await?;
+            return inner.close().await;
+        };
+        staged.file.sync_all().await.map_err(io_error)?;
+        let state = UploadState {
+            path: self.path.clone(),
+            size: self.size,
+            upload_url: None,
+            next_offset: 0,
+        };
+        self.uploader.save_state(&staged.id, &state).await?;
+        self.committed = true;
+        let id = staged.id.clone();
+        self.uploader.upload(&id, state).await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        if let Some(staged) = self.staged.take() {
+            log::debug!("abort upload {}", staged.id);
+            self.uploader.discard(&staged.id).await;
+        }
+        self.buffer = Vec::new();
+        match self.inner.as_mut() {
+            Some(inner) => inner.abort().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W> Drop for UploadWriter<W> {
+    fn drop(&mut self) {
+        // a write that never reached close can't be resumed
+        let Some(staged) = self.staged.take().filter(|_| !self.committed) else {
+            return;
+        };
+        let path = self.uploader.data_path(&staged.id);
+        match tokio::runtime::Handle::try_current() {
+            // the claim is held until the file is gone
+            Ok(handle) => {
+                handle.spawn(async move {
+                    let _ = tokio::fs::remove_file(path).await;
+                    drop(staged);
+                });
+            }
+            Err(_) => {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Marks an upload id as owned by a writer or a resume task.
+struct Claim {
+    uploader: Arc<Uploader>,
+    id: String,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.uploader.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+struct Uploader {
+    session: ODriveSession,
+    http_client: reqwest::Client,
+    root: String,
+    state_dir: PathBuf,
+    chunk_size: usize,
+    active: std::sync::Mutex<HashSet<String>>,
+}
+
+impl Uploader {
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.state_dir.join(format!("{}.data", id))
+    }
+
+    fn state_path(&self, id: &str) -> PathBuf {
+        self.state_dir.join(format!("{}.json", id))
+    }
+
+    fn claim(self: &Arc<Self>, id: &str) -> Option<Claim> {
+        if !self.active.lock().unwrap().insert(id.to_string()) {
+            return None;
+        }
+        Some(Claim { uploader: self.clone(), id: id.to_string() })
+    }
+
+    async fn stage(self: &Arc<Self>) -> Result<Staged> {
+        tokio::fs::create_dir_all(&self.state_dir).await.map_err(io_error)?;
+        let dir = self.state_dir.clone();
+        let (file, path) = tokio::task::spawn_blocking(move || {
+            tempfile::Builder::new().prefix("upload-").suffix(".data").tempfile_in(dir)?.keep().map_err(|e| e.error)
+        })
+            .await
+            .map_err(|e| Error::new(ErrorKind::Unexpected, "stage task failed").set_source(e))?
+            .map_err(io_error)?;
+        let id = path.file_stem().and_then(|s| s.to_str()).expect("staged file name is utf-8").to_string();
+        let claim = self.claim(&id).expect("fresh upload id is unclaimed");
+        Ok(Staged { id, file: File::from_std(file), _claim: claim })
+    }
+
+    async fn save_state(&self, id: &str, state: &UploadState) -> Result<()> {
+        let tmp = self.state_dir.join(format!("{}.json.tmp", id));
+        let json = serde_json::to_vec(state).map_err(|e| Error::new(ErrorKind::Unexpected, "failed to serialize upload state").set_source(e))?;
+        tokio::fs::write(&tmp, json).await.map_err(io_error)?;
+        tokio::fs::rename(&tmp, self.state_path(id)).await.map_err(io_error)
+    }
+
+    async fn load_state(&self, id: &str) -> Result<UploadState> {
+        let json = tokio::fs::read(self.state_path(id)).await.map_err(io_error)?;
+        serde_json::from_slice(&json).map_err(|e| Error::new(ErrorKind::Unexpected, "failed to deserialize upload state").set_source(e))
+    }
+
+    /// Remove the staged files and cancel the remote session if there is one.
+    async fn discard(&self, id: &str) {
+        if let Ok(UploadState { upload_url: Some(url), .. }) = self.load_state(id).await {
+            match self.http_client.delete(&url).send().await {
+                Ok(resp) => log::debug!("cancelled upload session {}: {}", id, resp.status()),
+                Err(e) => log::warn!("failed to cancel upload session {}: {}", id, e),
+            }
+        }
+        let _ = tokio::fs::remove_file(self.state_path(id)).await;
+        let _ = tokio::fs::remove_file(self.data_path(id)).await;
+    }
+
+    async fn resume_pending(self: &Arc<Self>) -> std::io::Result<()> {
+        if !tokio::fs::try_exists(&self.state_dir).await? {
+            return Ok(());
+        }
+        let mut entries = tokio::fs::read_dir(&self.state_dir).await?;
+        let mut ids = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if let (Some(id), Some("json" | "data")) = (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|s| s.to_str())) {
+                if !ids.iter().any(|i| i == id) {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+        for id in ids {
+            let Some(_claim) = self.claim(&id) else { continue };
+            let has_data = tokio::fs::try_exists(self.data_path(&id)).await?;
+            let state = match self.load_state(&id).await {
+                Ok(state) if has_data => state,
+                _ => {
+                    log::info!("dropping incomplete upload {}", id);
+                    self.discard(&id).await;
+                    continue;
+                }
+            };
+            log::info!("resuming upload {} of {} at {}/{}", id, state.path, state.next_offset, state.size);
+            if let Err(e) = self.upload(&id, state).await {
+                log::error!("resumed upload {} failed: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn upload(&self, id: &str, mut state: UploadState) -> Result<Metadata> {
+        let mut file = File::open(self.data_path(id)).await.map_err(io_error)?;
+        let mut attempts = 0;
+        loop {
+            if state.next_offset >= state.size {
+                // every byte went out but the final response was lost, the
+                // session is gone with it
+                log::warn!("upload {} has nothing left to send, starting over", id);
+                state.upload_url = None;
+            }
+            let url = match state.upload_url.clone() {
+                Some(url) => url,
+                None => {
+                    let url = self.create_session(&state.path).await?;
+                    state.upload_url = Some(url.clone());
+                    state.next_offset = 0;
+                    self.save_state(id, &state).await?;
+                    url
+                }
+            };
+            let len = (self.chunk_size as u64).min(state.size - state.next_offset) as usize;
+            let mut chunk = vec![0; len];
+            file.seek(SeekFrom::Start(state.next_offset)).await.map_err(io_error)?;
+            file.read_exact(&mut chunk).await.map_err(io_error)?;
+            let end = state.next_offset + len as u64 - 1;
+            log::debug!("upload {} bytes {}-{}/{}", id, state.next_offset, end, state.size);
+            let resp = self.http_client.put(&url)
+                .header(http::header::CONTENT_RANGE, format!("bytes {}-{}/{}", state.next_offset, end, state.size))
+                .header(http::header::CONTENT_LENGTH, len)
+                .body(chunk)
+                .send()
+                .await;
+            let err = match resp {
+                Ok(resp) if resp.status() == http::StatusCode::ACCEPTED => {
+                    let session: UploadSessionResponse = resp.json().await.map_err(http_error)?;
+                    state.next_offset = next_offset(&session).unwrap_or(end + 1);
+                    self.save_state(id, &state).await?;
+                    attempts = 0;
+                    continue;
+                }
+                Ok(resp) if resp.status().is_success() => {
+                    let item: DriveItem = resp.json().await.map_err(http_error)?;
+                    log::info!("uploaded {} ({} bytes)", state.path, state.size);
+                    let _ = tokio::fs::remove_file(self.state_path(id)).await;
+                    let _ = tokio::fs::remove_file(self.data_path(id)).await;
+                    return item_metadata(item);
+                }
+                Ok(resp) if resp.status() == http::StatusCode::NOT_FOUND => {
+                    log::warn!("upload session {} expired, starting over", id);
+                    state.upload_url = None;
+                    Error::new(ErrorKind::Unexpected, "upload session expired").set_temporary()
+                }
+                Ok(resp) if resp.status().is_server_error() || resp.status() == http::StatusCode::TOO_MANY_REQUESTS => {
+                    Error::new(ErrorKind::Unexpected, format!("upload chunk failed: {}", resp.status())).set_temporary()
+                }
+                Ok(resp) => {
+                    let status = resp.status();
+                    let body = resp.text().await.unwrap_or_default();
+                    self.discard(id).await;
+                    return Err(Error::new(ErrorKind::Unexpected, format!("upload chunk rejected: {} {}", status, body)));
+                }
+                Err(e) => http_error(e).set_temporary(),
+            };
+            attempts += 1;
+            if attempts >= MAX_CHUNK_ATTEMPTS {
+                // leave the session on disk for the next resume
+                return Err(err.set_persistent());
+            }
+            log::warn!("upload {} attempt {} failed: {}", id, attempts, err);
+            tokio::time::sleep(Duration::from_secs(1 << attempts)).await;
+            if let Some(url) = state.upload_url.as_ref() {
+                if let Some(offset) = self.session_offset(url).await {
+                    state.next_offset = offset;
+                }
+            }
+        }
+    }
+
+    async fn create_session(&self, path: &str) -> Result<String> {
+        let token = self.session.access_token().await
+            .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "onedrive access token not available"))?;
+        let resp = self.http_client.post(self.session_url(path)?)
+            .bearer_auth(token)
+            .json(&serde_json::json!({
+                "item": { "@microsoft.graph.conflictBehavior": "replace" }
+            }))
+            .send()
+            .await
+            .map_err(http_error)?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            return Err(Error::new(ErrorKind::Unexpected, format!("create upload session failed: {} {}", status, body)));
+        }
+        let session: UploadSessionResponse = resp.json().await.map_err(http_error)?;
+        session.upload_url.ok_or_else(|| Error::new(ErrorKind::Unexpected, "upload session without uploadUrl"))
+    }
+
+    /// Ask the session where to continue, `None` if it can't be reached.
+    async fn session_offset(&self, url: &str) -> Option<u64> {
+        let resp = self.http_client.get(url).send().await.ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        next_offset(&resp.json().await.ok()?)
+    }
+
+    fn session_url(&self, path: &str) -> Result<Url> {
+        let base = format!("{}:", self.session.drive_root_url());
+        let mut url = Url::parse(&base).map_err(|e| {
+            Error::new(ErrorKind::ConfigInvalid, "invalid drive url").with_context("url", &base).set_source(e)
+        })?;
+        let mut segments: Vec<&str> = self.root.split('/')
+            .chain(path.split('/'))
+            .filter(|s| !s.is_empty())
+            .collect();
+        let last = format!("{}:", segments.pop().unwrap_or_default());
+        url.path_segments_mut()
+            .map_err(|_| Error::new(ErrorKind::ConfigInvalid, "drive url can't have a path").with_context("url", &base))?
+            .extend(segments)
+            .push(&last)
+            .push("createUploadSession");
+        Ok(url)
+    }
+}
+
+fn next_offset(session: &UploadSessionResponse) -> Option<u64> {
+    session.next_expected_ranges.first()?.split('-').next()?.parse().ok()
+}
+
+fn item_metadata(item: DriveItem) -> Result<Metadata> {
+    let mut meta = Metadata::new(EntryMode::FILE);
+    if let Some(etag) = item.e_tag {
+        meta.set_etag(&etag);
+    }
+    if let Some(size) = item.size {
+        meta.set_content_length(size);
+    }
+    if let Some(last_modified) = item.last_modified_date_time {
+        meta.set_last_modified(parse_datetime_from_rfc3339(&last_modified)?);
+    }
+    Ok(meta)
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::Unexpected, "upload staging io failed").set_source(e)
+}
+
+fn http_error(e: reqwest::Error) -> Error {
+    Error::new(ErrorKind::Unexpected, "upload session request failed").set_source(e)
+}